@@ -1,9 +1,19 @@
+use std::path::PathBuf;
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Generate protobuf code
-    prost_build::compile_protos(&["src/api/v1/log.proto"], &["src/api/v1"])?;
-    
-    // Generate tonic code
-    tonic_build::compile_protos("src/api/v1/log.proto")?;
+    // Generate protobuf code for the records the log stores, in a directory
+    // of its own so the gRPC code below does not overwrite it
+    let log_out = PathBuf::from(std::env::var("OUT_DIR")?).join("log");
+    std::fs::create_dir_all(&log_out)?;
+    prost_build::Config::new()
+        .out_dir(&log_out)
+        .compile_protos(&["src/api/v1/log.proto"], &["src/api/v1"])?;
+
+    // Generate tonic code. Its byte fields are `Bytes`, so a record read out
+    // of a mapped segment is sent on without being copied.
+    tonic_build::configure()
+        .bytes(["."])
+        .compile_protos(&["src/api/v1/log.proto"], &["src/api/v1"])?;
 
     Ok(())
 }
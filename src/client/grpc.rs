@@ -1,5 +1,6 @@
 use tonic::{transport::Channel, Request};
 use std::net::SocketAddr;
+use std::ops::Range;
 use anyhow::Result;
 
 // Import the generated protobuf code
@@ -8,7 +9,7 @@ pub mod proto {
 }
 
 use proto::log_client::LogClient;
-use proto::{WriteRequest, WriteResponse, ReadRequest, ReadResponse, Record};
+use proto::{WriteRequest, WriteResponse, WriteBatchRequest, ReadRequest, ReadResponse, OffsetsRequest, OffsetForTimestampRequest, Record};
 
 #[derive(Clone)]
 pub struct WalClient {
@@ -28,10 +29,16 @@ impl WalClient {
 
     pub async fn write(&mut self, data: Vec<u8>, offset: u64) -> Result<u64> {
         let record = Record {
-            value: data,
+            value: data.into(),
             offset,
+            ..Default::default()
         };
-        
+
+        self.write_record(record).await
+    }
+
+    // Write a record along with its key, headers and timestamp
+    pub async fn write_record(&mut self, record: Record) -> Result<u64> {
         let request = Request::new(WriteRequest {
             record: Some(record),
         });
@@ -40,15 +47,39 @@ impl WalClient {
         Ok(response.into_inner().offset)
     }
 
+    // Write several values in one request, returning the offsets they were assigned
+    pub async fn write_batch(&mut self, values: Vec<Vec<u8>>) -> Result<Range<u64>> {
+        let records = values
+            .into_iter()
+            .map(|value| Record { value: value.into(), ..Default::default() })
+            .collect();
+
+        self.write_record_batch(records).await
+    }
+
+    // Write several full records in one request
+    pub async fn write_record_batch(&mut self, records: Vec<Record>) -> Result<Range<u64>> {
+        let request = Request::new(WriteBatchRequest { records });
+
+        let response = self.client.write_batch(request).await?.into_inner();
+        Ok(response.start_offset..response.end_offset)
+    }
+
     pub async fn read(&mut self, offset: u64) -> Result<Option<Vec<u8>>> {
+        Ok(self.read_record(offset).await?.map(|r| r.value.into()))
+    }
+
+    // Read a record along with its key, headers and timestamp. None if the
+    // node has no record at the offset, whether it is outside its log or
+    // was removed by compaction.
+    pub async fn read_record(&mut self, offset: u64) -> Result<Option<Record>> {
         let request = Request::new(ReadRequest { offset });
         
         match self.client.read(request).await {
             Ok(response) => {
-                let record = response.into_inner().record;
-                Ok(record.map(|r| r.value))
+                Ok(response.into_inner().record)
             }
-            Err(status) if status.code() == tonic::Code::NotFound => {
+            Err(status) if matches!(status.code(), tonic::Code::NotFound | tonic::Code::OutOfRange) => {
                 Ok(None)
             }
             Err(e) => {
@@ -56,4 +87,26 @@ impl WalClient {
             }
         }
     }
+
+    // The lowest and highest offsets held by the node, or None if its log is empty
+    pub async fn offsets(&mut self) -> Result<Option<(u64, u64)>> {
+        let response = self.client.offsets(Request::new(OffsetsRequest {})).await?.into_inner();
+
+        if response.empty {
+            return Ok(None);
+        }
+        Ok(Some((response.lowest_offset, response.highest_offset)))
+    }
+
+    // The first offset whose record timestamp is at or after `timestamp`
+    // (milliseconds since the Unix epoch), or None if every record is older
+    pub async fn offset_for_timestamp(&mut self, timestamp: u64) -> Result<Option<u64>> {
+        let request = Request::new(OffsetForTimestampRequest { timestamp });
+        let response = self.client.offset_for_timestamp(request).await?.into_inner();
+
+        if !response.found {
+            return Ok(None);
+        }
+        Ok(Some(response.offset))
+    }
 }
@@ -21,8 +21,12 @@ pub struct ClusterConfig {
     pub data_dir: String,
     /// Maximum segment size in bytes
     pub max_segment_bytes: u64,
-    /// Maximum index size in bytes
+    /// Index file size to preallocate in bytes, grown as needed
     pub max_index_bytes: u64,
+    /// How long concurrent writes are collected into one group commit, in microseconds
+    pub group_commit_window_us: u64,
+    /// Most writes collected into one group commit
+    pub group_commit_max_records: usize,
 }
 
 impl Default for ClusterConfig {
@@ -37,6 +41,8 @@ impl Default for ClusterConfig {
             data_dir: "/tmp/walrus".to_string(),
             max_segment_bytes: 1024 * 1024, // 1MB
             max_index_bytes: 1024 * 1024,   // 1MB
+            group_commit_window_us: 500,
+            group_commit_max_records: 1024,
         }
     }
 }
@@ -64,4 +70,8 @@ impl ClusterConfig {
     pub fn replication_timeout(&self) -> Duration {
         Duration::from_millis(self.replication_timeout_ms)
     }
+
+    pub fn group_commit_window(&self) -> Duration {
+        Duration::from_micros(self.group_commit_window_us)
+    }
 }
@@ -1,5 +1,6 @@
 use crate::cluster::state::{ClusterStateManager, NodeRole};
 use crate::cluster::config::ClusterConfig;
+use crate::log::error::LogError;
 use crate::log::segment::Record;
 use anyhow::Result;
 use async_trait::async_trait;
@@ -204,7 +205,7 @@ impl ReplicationManager {
             record.value = entry.command.clone();
             record.offset = entry.index;
             
-            let mut log_guard = self.log.lock().unwrap();
+            let mut log_guard = self.log.write().unwrap();
             if let Err(e) = log_guard.append(&mut record) {
                 warn!("Failed to append log entry: {}", e);
                 return Ok(ReplicationResponse {
@@ -249,7 +250,7 @@ impl ReplicationManager {
         record.value = entry.command.clone();
         record.offset = entry.index;
         
-        let mut log_guard = self.log.lock().unwrap();
+        let mut log_guard = self.log.write().unwrap();
         let offset = log_guard.append(&mut record).map_err(|e| anyhow::anyhow!("Failed to append record: {}", e))?;
         drop(log_guard);
 
@@ -269,11 +270,12 @@ impl ReplicationManager {
     }
 
     pub async fn read_entry(&self, index: u64) -> Result<Option<Vec<u8>>> {
-        let mut log_guard = self.log.lock().unwrap();
+        let log_guard = self.log.read().unwrap();
         
         match log_guard.read(index) {
             Ok(record) => Ok(Some(record.value)),
-            Err(_) => Ok(None),
+            Err(LogError::OffsetOutOfRange { .. }) | Err(LogError::OffsetRemoved(_)) => Ok(None),
+            Err(e) => Err(e.into()),
         }
     }
 
@@ -0,0 +1,107 @@
+use super::error::LogError;
+use super::log::SafeLog;
+use super::segment;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tracing::error;
+
+// Custom Result type to match other modules
+pub type Result<T> = std::result::Result<T, LogError>;
+
+// An append waiting for the next group commit
+struct Pending {
+    record: segment::Record,
+    reply: oneshot::Sender<Result<u64>>,
+}
+
+// Funnels concurrent appends into shared batches. A dedicated thread takes
+// the first waiting append, keeps collecting more for up to `window` (or
+// until `max_records` have arrived), writes them with one `append_batch`
+// and then wakes every caller with its offset. The batch is committed once,
+// so it is synced according to the log's durability mode: under every-write
+// the whole batch shares one fsync and each caller only hears back once its
+// record is on disk. A batch that fails is rolled back, so callers that get
+// an error can retry without leaving duplicates.
+#[derive(Clone)]
+pub struct GroupCommit {
+    sender: Sender<Pending>,
+    // Batches written so far
+    commits: Arc<AtomicU64>,
+}
+
+impl GroupCommit {
+    pub fn new(log: SafeLog, window: Duration, max_records: usize) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let commits = Arc::new(AtomicU64::new(0));
+
+        // The thread exits once every handle has been dropped
+        let counter = commits.clone();
+        thread::Builder::new()
+            .name("walrus-group-commit".to_string())
+            .spawn(move || run(log, receiver, window, max_records.max(1), counter))
+            .expect("failed to spawn group commit thread");
+
+        Self { sender, commits }
+    }
+
+    // How many batches have been written, each with a single `append_batch`
+    pub fn commits(&self) -> u64 {
+        self.commits.load(Ordering::Relaxed)
+    }
+
+    // Append a record as part of the next group commit, returning its
+    // offset once it has been synced
+    pub async fn append(&self, record: segment::Record) -> Result<u64> {
+        let (reply, response) = oneshot::channel();
+        self.sender
+            .send(Pending { record, reply })
+            .map_err(|_| LogError::Unavailable("Group commit thread has stopped".to_string()))?;
+
+        response
+            .await
+            .map_err(|_| LogError::Unavailable("Group commit thread dropped the append".to_string()))?
+    }
+}
+
+fn run(log: SafeLog, receiver: Receiver<Pending>, window: Duration, max_records: usize, commits: Arc<AtomicU64>) {
+    while let Ok(first) = receiver.recv() {
+        let mut batch = vec![first];
+
+        // Collect whatever else turns up within the window
+        let deadline = Instant::now() + window;
+        while batch.len() < max_records {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            match receiver.recv_timeout(deadline - now) {
+                Ok(pending) => batch.push(pending),
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        let (mut records, replies): (Vec<_>, Vec<_>) =
+            batch.into_iter().map(|pending| (pending.record, pending.reply)).unzip();
+
+        let result = log.write().unwrap().append_batch(&mut records);
+        commits.fetch_add(1, Ordering::Relaxed);
+
+        match result {
+            Ok(offsets) => {
+                for (reply, offset) in replies.into_iter().zip(offsets) {
+                    let _ = reply.send(Ok(offset));
+                }
+            }
+            Err(e) => {
+                error!("Group commit of {} records failed: {}", replies.len(), e);
+                for reply in replies {
+                    let _ = reply.send(Err(e.clone()));
+                }
+            }
+        }
+    }
+}
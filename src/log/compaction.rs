@@ -0,0 +1,173 @@
+use super::reader::{LogReader, SegmentRange};
+use super::segment::{self, Record, Segment};
+use super::encryption::KeyRing;
+use super::error::LogError;
+use super::{config, store};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+// Custom Result type to match other modules
+pub type Result<T> = std::result::Result<T, LogError>;
+
+// Segments are rewritten in this directory under the log directory and only
+// moved over the originals once the rewrite is complete
+const WORK_DIR: &str = "compaction";
+// Written once every rewritten file is on disk. An interrupted compaction
+// is finished on startup if this is there and thrown away if it is not.
+const READY_MARKER: &str = "ready";
+
+// A record with a key and an empty value marks its key as deleted
+pub fn is_tombstone(record: &Record) -> bool {
+    !record.key.is_empty() && record.value.is_empty()
+}
+
+// Decides which records in the closed segments survive compaction, given
+// where the latest record for every key in the log is
+pub(crate) struct Cleaner {
+    latest: HashMap<Vec<u8>, u64>,
+    tombstone_cutoff: u64,
+    keys: Option<Arc<KeyRing>>,
+}
+
+impl Cleaner {
+    // Read through the whole log noting the latest offset for each key
+    pub fn new(reader: LogReader, conf: &config::Config) -> Result<Self> {
+        let mut latest = HashMap::new();
+        for record in reader {
+            let record = record?;
+            if !record.key.is_empty() {
+                latest.insert(record.key, record.offset);
+            }
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        Ok(Self {
+            latest,
+            tombstone_cutoff: now.saturating_sub(conf.compaction.tombstone_retention_ms),
+            keys: conf.encryption.clone(),
+        })
+    }
+
+    // Records without a key are always kept. Keyed ones are kept while they
+    // are the latest for their key, unless they are a tombstone that has
+    // been around for longer than the tombstone retention.
+    fn keep(&self, record: &Record) -> bool {
+        if record.key.is_empty() {
+            return true;
+        }
+        if self.latest.get(&record.key) != Some(&record.offset) {
+            return false;
+        }
+        !(is_tombstone(record) && record.timestamp <= self.tombstone_cutoff)
+    }
+
+    // How many of the records would be kept and how many removed
+    pub fn count(&self, records: LogReader) -> Result<(usize, usize)> {
+        let (mut kept, mut removed) = (0, 0);
+        for record in records {
+            if self.keep(&record?) {
+                kept += 1;
+            } else {
+                removed += 1;
+            }
+        }
+
+        Ok((kept, removed))
+    }
+
+    // Write the records that are kept into a new segment with the given base
+    // offset in the work directory. Nothing in the log directory changes
+    // until the rewrite is swapped in, so this can run without the log
+    // locked, from records opened while it was.
+    pub fn rewrite(&self, dir: &str, base_offset: u64, records: LogReader, conf: &config::Config) -> Result<()> {
+        let work_dir = format!("{}/{}", dir, WORK_DIR);
+        if Path::new(&work_dir).exists() {
+            fs::remove_dir_all(&work_dir)?;
+        }
+        fs::create_dir_all(&work_dir)?;
+
+        // Nothing is acknowledged from the rewrite, so there is no point
+        // syncing it record by record. Closing it syncs everything once.
+        let mut conf = conf.clone();
+        conf.durability = config::Durability::OsManaged;
+
+        let mut cleaned = segment::new(&work_dir, format!("{}/{}", work_dir, base_offset), base_offset, conf)?;
+        for record in records {
+            let record = record?;
+            if self.keep(&record) {
+                cleaned.append_existing(&record)?;
+            }
+        }
+        cleaned.close()?;
+
+        Ok(())
+    }
+
+    // Every record in the segment, in offset order. The file is opened
+    // straight away, so the reader keeps working if the segment is replaced
+    // or deleted after the log is unlocked.
+    pub fn records(&self, segment: &Segment) -> Result<LogReader> {
+        let range = SegmentRange::open(
+            segment.store_path(),
+            store::HEADER_WIDTH,
+            segment.size(),
+            segment.next_offset(),
+        )?;
+        Ok(LogReader::new(segment.base_offset(), vec![range], self.keys.clone()))
+    }
+}
+
+// Move a finished rewrite over the segment it replaces. The segment has to
+// be reopened afterwards to see the rewritten files.
+pub(crate) fn swap(dir: &str) -> Result<()> {
+    let work_dir = format!("{}/{}", dir, WORK_DIR);
+    File::create(format!("{}/{}", work_dir, READY_MARKER))?.sync_all()?;
+    File::open(&work_dir)?.sync_all()?;
+
+    finish(dir)
+}
+
+// Throw away a rewrite that no longer matches its segment
+pub(crate) fn discard(dir: &str) -> Result<()> {
+    fs::remove_dir_all(format!("{}/{}", dir, WORK_DIR))?;
+    Ok(())
+}
+
+// Deal with a compaction that was interrupted by the process going away,
+// either finishing the swap or discarding the half written segment
+pub(crate) fn recover(dir: &str) -> Result<()> {
+    let work_dir = format!("{}/{}", dir, WORK_DIR);
+    if !Path::new(&work_dir).exists() {
+        return Ok(());
+    }
+
+    if Path::new(&work_dir).join(READY_MARKER).exists() {
+        warn!("Finishing interrupted compaction in {}", dir);
+        finish(dir)
+    } else {
+        warn!("Discarding incomplete compaction in {}", dir);
+        fs::remove_dir_all(&work_dir)?;
+        Ok(())
+    }
+}
+
+// Move the rewritten segment files over the originals and clear the work
+// directory. Safe to run again if it was itself interrupted.
+fn finish(dir: &str) -> Result<()> {
+    let work_dir = format!("{}/{}", dir, WORK_DIR);
+    for entry in fs::read_dir(&work_dir)? {
+        let entry = entry?;
+        if entry.file_name() == READY_MARKER {
+            continue;
+        }
+        fs::rename(entry.path(), Path::new(dir).join(entry.file_name()))?;
+    }
+    File::open(dir)?.sync_all()?;
+    fs::remove_dir_all(&work_dir)?;
+
+    Ok(())
+}
@@ -1,13 +1,101 @@
+use super::encryption;
+use std::sync::Arc;
+
 // Defaults for initializing segments
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct InitSegment {
     pub max_store_bytes: u64,
+    // Room index files are created with. They grow past it as needed, so it
+    // does not limit how many records a segment holds.
     pub max_index_bytes: u64,
     pub initial_offset: u64,
+    // Store bytes between index entries. 0 indexes every record, anything
+    // larger keeps the index sparse and reads scan forward from the nearest
+    // entry before the offset they want.
+    pub index_interval_bytes: u64,
+    // Records a segment holds before the log rolls to a new one. Indexes
+    // store offsets relative to the segment's base offset as u32, so this
+    // can be at most `segment::MAX_RECORDS_PER_SEGMENT`, and 0 means that.
+    pub max_records_per_segment: u64,
+}
+
+// Policies for deleting old segments. Only closed segments are ever
+// removed, and a value of 0 turns that particular policy off.
+#[derive(Clone, Default)]
+pub struct Retention {
+    // Upper bound on the store bytes held across all segments
+    pub max_bytes: u64,
+    // Segments whose newest record is older than this are removed
+    pub max_age_ms: u64,
+    // Segments holding only offsets below this one are removed
+    pub min_offset: u64,
+}
+
+// Key-based compaction of closed segments, which keeps only the latest
+// record for each key
+#[derive(Clone, Default)]
+pub struct Compaction {
+    // How long a tombstone (a record with a key and an empty value) is kept
+    // once it is the latest record for its key, measured from its timestamp.
+    // Gives readers a chance to see the delete before the key disappears.
+    pub tombstone_retention_ms: u64,
+}
+
+// Free space kept in hand on the volume holding the log. An append that
+// would eat into the reserve is refused and the log goes read-only until
+// space is freed, rather than the disk running dry part way through a
+// frame. 0 turns the guard off.
+#[derive(Clone, Default)]
+pub struct Disk {
+    pub reserve_bytes: u64,
+}
+
+// How hard the store pushes appended data out to disk. Whatever the mode,
+// every append is handed to the OS before it returns and a segment is
+// fsynced when it is closed; this decides when the active segment's store
+// and index are fsynced in between.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Durability {
+    // fsync after every append, nothing acknowledged is lost on a crash
+    EveryWrite,
+    // fsync once `bytes` have been written or `interval_ms` has passed
+    // since the last sync, whichever comes first
+    Periodic { bytes: u64, interval_ms: u64 },
+    // Leave writing back dirty pages to the OS
+    OsManaged,
+}
+
+impl Default for Durability {
+    fn default() -> Self {
+        Durability::Periodic {
+            bytes: 256 * 1024, // 256 KB -> Reduces the volume of sys calls.
+            interval_ms: 1000,
+        }
+    }
+}
+
+// Codec record payloads are compressed with in the store. Every frame
+// notes the codec it was written with, so changing this only affects new
+// appends and segments holding a mix of codecs stay readable.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+    Snappy,
 }
 
 // Configuration object for handling segments
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct Config {
     pub segment: InitSegment,
+    pub retention: Retention,
+    pub compaction: Compaction,
+    pub disk: Disk,
+    pub durability: Durability,
+    pub compression: Compression,
+    // Keys to encrypt record payloads with in the store. Records are written
+    // in the clear without them, and encrypted ones cannot be read.
+    pub encryption: Option<Arc<encryption::KeyRing>>,
 }
@@ -0,0 +1,100 @@
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use super::error::LogError;
+use std::collections::HashMap;
+use std::fs;
+
+// Custom Result type to match other modules
+pub type Result<T> = std::result::Result<T, LogError>;
+
+const KEY_ID_WIDTH: usize = 4;
+const KEY_WIDTH: usize = 32;
+// XChaCha20-Poly1305 nonces are wide enough to be picked at random for every
+// record without a realistic chance of two ever colliding under one key,
+// which the 12 byte nonces of plain ChaCha20-Poly1305 only manage for
+// around 2^32 records
+const NONCE_WIDTH: usize = 24;
+
+// The keys record payloads are encrypted with, loaded from a key file. Each
+// line of the file holds a numeric key ID and a hex encoded 256-bit key,
+// separated by whitespace. Blank lines and lines starting with `#` are
+// ignored. New records are encrypted with the last key listed, and every key
+// is kept for reading, so a key is rotated by adding a line to the end and
+// restarting. Old segments stay readable for as long as their key is listed.
+pub struct KeyRing {
+    keys: HashMap<u32, XChaCha20Poly1305>,
+    active: u32,
+}
+
+pub fn load(path: &str) -> Result<KeyRing> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| LogError::Encryption(format!("Failed to read key file {}: {}", path, e)))?;
+
+    let mut keys = HashMap::new();
+    let mut active = None;
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let invalid = |reason: &str| LogError::Encryption(format!("{} line {}: {}", path, number + 1, reason));
+        let mut fields = line.split_whitespace();
+        let (Some(id), Some(key), None) = (fields.next(), fields.next(), fields.next()) else {
+            return Err(invalid("expected a key ID and a key"));
+        };
+
+        let id: u32 = id.parse().map_err(|_| invalid("key ID is not a number"))?;
+        let key = hex::decode(key).map_err(|_| invalid("key is not valid hex"))?;
+        if key.len() != KEY_WIDTH {
+            return Err(invalid(&format!("key is {} bytes, expected {}", key.len(), KEY_WIDTH)));
+        }
+        if keys.insert(id, XChaCha20Poly1305::new(Key::from_slice(&key))).is_some() {
+            return Err(invalid(&format!("key ID {} is listed twice", id)));
+        }
+        active = Some(id);
+    }
+
+    let active = active.ok_or_else(|| LogError::Encryption(format!("Key file {} holds no keys", path)))?;
+    Ok(KeyRing { keys, active })
+}
+
+impl KeyRing {
+    // ID of the key new records are encrypted with
+    pub fn active_key_id(&self) -> u32 {
+        self.active
+    }
+
+    // Encrypt with the active key, laid out as
+    // [key ID: u32][nonce: 24 bytes][ciphertext and tag]
+    // `aad` is authenticated along with the payload but not stored.
+    pub fn encrypt(&self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce: [u8; NONCE_WIDTH] = rand::random();
+        let ciphertext = self.keys[&self.active]
+            .encrypt(XNonce::from_slice(&nonce), Payload { msg: plaintext, aad })
+            .map_err(|_| LogError::Encryption("Failed to encrypt payload".to_string()))?;
+
+        let mut sealed = Vec::with_capacity(KEY_ID_WIDTH + NONCE_WIDTH + ciphertext.len());
+        sealed.extend_from_slice(&self.active.to_be_bytes());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    // Reverse `encrypt` using whichever key the payload names
+    pub fn decrypt(&self, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < KEY_ID_WIDTH + NONCE_WIDTH {
+            return Err(LogError::Encryption("Encrypted payload is too short".to_string()));
+        }
+        let (id, rest) = sealed.split_at(KEY_ID_WIDTH);
+        let (nonce, ciphertext) = rest.split_at(NONCE_WIDTH);
+
+        let id = u32::from_be_bytes(id.try_into().unwrap());
+        let cipher = self.keys.get(&id)
+            .ok_or_else(|| LogError::Encryption(format!("No key with ID {} in the key file", id)))?;
+        let plaintext = cipher
+            .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+            .map_err(|_| LogError::Encryption(format!("Payload failed to authenticate with key {}", id)))?;
+        Ok(plaintext)
+    }
+}
@@ -0,0 +1,72 @@
+use super::store::CorruptRecord;
+use std::io;
+use std::sync::Arc;
+use thiserror::Error;
+
+// Everything that can go wrong in the log module. Errors are Clone so that a
+// group commit can hand the same failure back to every append in the batch,
+// which is why the I/O errors are kept behind an Arc.
+#[derive(Debug, Clone, Error)]
+pub enum LogError {
+    // The offset is not one the log or segment holds
+    #[error("Offset {offset} is out of range, the log holds offsets {start}..{end}")]
+    OffsetOutOfRange { offset: u64, start: u64, end: u64 },
+
+    // The offset is within the log but its record was removed by compaction
+    #[error("Offset {0} is not in the log, its record was removed by compaction")]
+    OffsetRemoved(u64),
+
+    // A record was written at an offset before the end of its segment
+    #[error("Offset {offset} is before the end of segment {base_offset} at {next_offset}")]
+    OffsetOutOfOrder { offset: u64, base_offset: u64, next_offset: u64 },
+
+    // Relative offsets are u32, so a segment can only hold so many records
+    #[error("Segment {base_offset} cannot hold more than {max_records} records")]
+    SegmentFull { base_offset: u64, max_records: u64 },
+
+    #[error(transparent)]
+    Corrupt(#[from] CorruptRecord),
+
+    #[error("Failed to decode record: {0}")]
+    Decode(#[from] prost::DecodeError),
+
+    // A file that is not in a format this build can read
+    #[error("{0}")]
+    InvalidFormat(String),
+
+    // Missing or bad keys, and payloads that fail to decrypt
+    #[error("{0}")]
+    Encryption(String),
+
+    #[error("{0}")]
+    InvalidConfig(String),
+
+    #[error("Log directory {dir} is already in use by another process (pid {pid}, see {path})")]
+    DirectoryLocked { dir: String, pid: String, path: String },
+
+    // Appends are refused until the free space is back above the reserve
+    #[error("Log is read-only, {available} bytes are free on its volume against a reserve of {reserve}")]
+    ReadOnly { available: u64, reserve: u64 },
+
+    // The disk or the quota ran out of room
+    #[error("Out of disk space: {0}")]
+    DiskFull(Arc<io::Error>),
+
+    #[error("I/O error: {0}")]
+    Io(Arc<io::Error>),
+
+    // The group commit thread is gone, so appends through it cannot complete
+    #[error("{0}")]
+    Unavailable(String),
+}
+
+impl From<io::Error> for LogError {
+    fn from(e: io::Error) -> Self {
+        // Matched on the errno, as the ErrorKinds for these need a newer
+        // toolchain than the one the project builds with
+        match e.raw_os_error() {
+            Some(libc::ENOSPC) | Some(libc::EDQUOT) => LogError::DiskFull(Arc::new(e)),
+            _ => LogError::Io(Arc::new(e)),
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use super::error::LogError;
+use super::segment::Record;
+use super::{config, index, store};
+use byteorder::{BigEndian, ByteOrder};
+use prost::Message;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read};
+use tracing::{info, warn};
+
+// Custom Result type to match other modules
+pub type Result<T> = std::result::Result<T, LogError>;
+
+// On-disk format version shared by the `.store` and `.index` files of a segment.
+//
+// Version 1 is the original headerless layout: the store is a sequence of
+// [len: u64][payload] frames and the index a bare run of 12 byte entries.
+// Version 2 adds a magic + version header to both files and checksummed
+// store frames. Version 3 adds an attributes byte to each store frame,
+// between the checksum and the payload, saying how the payload is
+// compressed and whether it is encrypted, and covers it with the checksum.
+// Bump this whenever either layout changes, and teach `upgrade_segment` how
+// to get from the old version to the new one.
+pub const LEGACY_VERSION: u32 = 1;
+pub const V2: u32 = 2;
+pub const VERSION: u32 = 3;
+
+const LEGACY_LEN_WIDTH: usize = 8;
+// Version 2 frames are [len: u64][crc32c of the payload: u32][payload]
+const V2_FRAME_HEADER_WIDTH: usize = 12;
+
+// Work out which format version the store at `path` was written with
+pub fn store_version(path: &str) -> Result<u32> {
+    let mut file = File::open(path)?;
+    let mut header = [0u8; store::HEADER_WIDTH as usize];
+
+    match file.read_exact(&mut header) {
+        Ok(()) => {}
+        // Too short to hold a header. An empty store is simply a new one,
+        // anything else can only have come from the headerless format.
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+            return Ok(if file.metadata()?.len() == 0 { VERSION } else { LEGACY_VERSION });
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    if &header[..store::MAGIC.len()] != store::MAGIC {
+        return Ok(LEGACY_VERSION);
+    }
+    Ok(BigEndian::read_u32(&header[store::MAGIC.len()..]))
+}
+
+// Bring the segment with the given base offset up to the current format.
+// Segments already at the current version are left alone.
+pub fn upgrade_segment(dir: &str, base_offset: u64, conf: &config::Config) -> Result<()> {
+    let store_path = format!("{}/{}.store", dir, base_offset);
+    let version = store_version(&store_path)?;
+
+    match version {
+        VERSION => Ok(()),
+        LEGACY_VERSION => upgrade_from_legacy(dir, base_offset, conf),
+        V2 => upgrade_from_v2(dir, base_offset, conf),
+        _ => Err(LogError::InvalidFormat(format!(
+            "{} has segment format version {}, this build supports up to {}",
+            store_path, version, VERSION
+        ))),
+    }
+}
+
+// Rewrite a headerless segment into the current format. The new files are
+// written alongside the old ones and renamed into place, index first, so a
+// crash part way through leaves a store that is still detected as legacy and
+// gets upgraded again on the next start.
+fn upgrade_from_legacy(dir: &str, base_offset: u64, conf: &config::Config) -> Result<()> {
+    let store_path = format!("{}/{}.store", dir, base_offset);
+    let index_path = format!("{}/{}.index", dir, base_offset);
+    let new_store_path = format!("{}.upgrade", store_path);
+    let new_index_path = format!("{}.upgrade", index_path);
+
+    info!("Upgrading segment {} from format version {} to {}", base_offset, LEGACY_VERSION, VERSION);
+
+    let (new_store, mut new_index) = create_upgrade_files(&new_store_path, &new_index_path, conf)?;
+
+    // Copy every complete legacy frame across. The legacy index is not
+    // trusted, it is rebuilt from the frames actually present in the store.
+    let legacy_store = File::open(&store_path)?;
+    let mut remaining = legacy_store.metadata()?.len();
+    let mut reader = BufReader::new(legacy_store);
+    let mut records: u64 = 0;
+    let mut len_buf = [0u8; LEGACY_LEN_WIDTH];
+    {
+        let mut safe_store = new_store.lock().unwrap();
+        loop {
+            match reader.read_exact(&mut len_buf) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+
+            remaining -= LEGACY_LEN_WIDTH as u64;
+
+            // Without checksums a torn frame can only be spotted by running
+            // off the end of the file
+            let len = BigEndian::read_u64(&len_buf);
+            if len > remaining {
+                warn!("Dropping torn legacy frame at the end of segment {}", base_offset);
+                break;
+            }
+            remaining -= len;
+
+            let mut payload = vec![0u8; len as usize];
+            reader.read_exact(&mut payload)?;
+
+            let (_, position) = safe_store.write(&payload)?;
+            new_index.append(records as u32, position)?;
+            records += 1;
+        }
+
+        safe_store.close()?;
+    }
+    new_index.close()?;
+
+    fs::rename(&new_index_path, &index_path)?;
+    fs::rename(&new_store_path, &store_path)?;
+
+    info!("Upgraded segment {} ({} records)", base_offset, records);
+    Ok(())
+}
+
+// Rewrite a version 2 segment into the current format. Every frame gains an
+// attributes byte of zero, as version 2 payloads are stored as they are, so
+// every position moves and the index is rebuilt from the records. The time
+// index holds no positions but does carry the version, so it is dropped and
+// rebuilt from the records when the segment is opened. Renames go index
+// first as with the legacy upgrade.
+fn upgrade_from_v2(dir: &str, base_offset: u64, conf: &config::Config) -> Result<()> {
+    let store_path = format!("{}/{}.store", dir, base_offset);
+    let index_path = format!("{}/{}.index", dir, base_offset);
+    let timeindex_path = format!("{}/{}.timeindex", dir, base_offset);
+    let new_store_path = format!("{}.upgrade", store_path);
+    let new_index_path = format!("{}.upgrade", index_path);
+
+    info!("Upgrading segment {} from format version {} to {}", base_offset, V2, VERSION);
+
+    // Payloads are copied across untouched, whatever new records are
+    // written with
+    let mut conf = conf.clone();
+    conf.compression = config::Compression::None;
+    conf.encryption = None;
+    let (new_store, mut new_index) = create_upgrade_files(&new_store_path, &new_index_path, &conf)?;
+
+    let old_store = File::open(&store_path)?;
+    let mut remaining = old_store.metadata()?.len() - store::HEADER_WIDTH;
+    let mut reader = BufReader::new(old_store);
+    reader.read_exact(&mut [0u8; store::HEADER_WIDTH as usize])?;
+
+    let mut pos = store::HEADER_WIDTH;
+    let mut records: u64 = 0;
+    let mut header = [0u8; V2_FRAME_HEADER_WIDTH];
+    {
+        let mut safe_store = new_store.lock().unwrap();
+        while remaining > 0 {
+            if remaining < V2_FRAME_HEADER_WIDTH as u64 {
+                warn!("Dropping torn frame at the end of segment {}", base_offset);
+                break;
+            }
+            reader.read_exact(&mut header)?;
+            remaining -= V2_FRAME_HEADER_WIDTH as u64;
+
+            let len = BigEndian::read_u64(&header[..LEGACY_LEN_WIDTH]);
+            if len > remaining {
+                warn!("Dropping torn frame at the end of segment {}", base_offset);
+                break;
+            }
+            remaining -= len;
+
+            let mut payload = vec![0u8; len as usize];
+            reader.read_exact(&mut payload)?;
+
+            // A bad checksum on the last frame is a write that never fully
+            // made it to disk. Anywhere else the store is damaged.
+            let expected_crc = BigEndian::read_u32(&header[LEGACY_LEN_WIDTH..]);
+            if crc32c::crc32c(&payload) != expected_crc {
+                if remaining == 0 {
+                    warn!("Dropping torn frame at the end of segment {}", base_offset);
+                    break;
+                }
+                return Err(store::corrupt(&store_path, pos, "checksum mismatch"));
+            }
+
+            // Compaction can leave gaps, so the offset comes from the record
+            // rather than from counting frames
+            let record = Record::decode(&payload[..])?;
+            let relative_offset = record
+                .offset
+                .checked_sub(base_offset)
+                .and_then(|relative| u32::try_from(relative).ok())
+                .ok_or_else(|| store::corrupt(&store_path, pos, &format!(
+                    "record offset {} does not belong in segment {}", record.offset, base_offset)))?;
+
+            let (_, position) = safe_store.write(&payload)?;
+            new_index.append(relative_offset, position)?;
+            records += 1;
+            pos += V2_FRAME_HEADER_WIDTH as u64 + len;
+        }
+
+        safe_store.close()?;
+    }
+    new_index.close()?;
+
+    if let Err(e) = fs::remove_file(&timeindex_path) {
+        if e.kind() != ErrorKind::NotFound {
+            return Err(e.into());
+        }
+    }
+    fs::rename(&new_index_path, &index_path)?;
+    fs::rename(&new_store_path, &store_path)?;
+
+    info!("Upgraded segment {} ({} records)", base_offset, records);
+    Ok(())
+}
+
+// Start the store and index an upgrade is written to, clearing out any left
+// over from an earlier attempt that did not finish
+fn create_upgrade_files(store_path: &str, index_path: &str, conf: &config::Config) -> Result<(store::SafeStore, index::Index)> {
+    let _ = fs::remove_file(store_path);
+    let _ = fs::remove_file(index_path);
+
+    let store_file = OpenOptions::new()
+        .read(true)
+        .create(true)
+        .append(true)
+        .open(store_path)?;
+    let store = store::new(&store_file, store_path.to_string(), conf)?;
+
+    let index_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(index_path)?;
+    let index = index::new(&index_file, index_path.to_string(), conf)?;
+
+    Ok((store, index))
+}
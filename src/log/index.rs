@@ -1,151 +1,139 @@
-use crate::log::config;
-use memmap2::MmapMut;
+use crate::log::error::LogError;
+use crate::log::{config, mapped};
 use std::fs::File;
 use std::io::{Error, ErrorKind};
 
 // Custom Result type to match other modules
-pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+pub type Result<T> = std::result::Result<T, LogError>;
 
 const OFF_WIDTH: u64 = 4;
 const POS_WIDTH: u64 = 8;
 const ENT_WIDTH: u64 = OFF_WIDTH + POS_WIDTH;
 
+// Entries are [relative offset: u32][store position: u64], after the header
+// described in `mapped`
+const MAGIC: &[u8; 4] = b"WIDX";
+
 pub struct Index {
-    pub file: File,
-    pub path: String,
-    mmap: MmapMut,
-    pub size: u64,
+    file: mapped::MappedFile,
+    // Store bytes to leave between entries, 0 to index every record
+    interval_bytes: u64,
 }
 
 pub fn new(file: &File, path: String, conf: &config::Config) -> Result<Index> {
-    let file_size = file.metadata()?.len() as u64;
-    let file_obj = file.try_clone()?;
-    
-    // Ensure the file is large enough for the memory map
-    let max_size = conf.segment.max_index_bytes;
-    if file_size < max_size {
-        file_obj.set_len(max_size)?;
-    }
-    
-    let mmap = unsafe { MmapMut::map_mut(&file_obj)? };
-
-    // Calculate the actual data size by finding the last valid entry
-    let mut actual_size = 0;
-    if file_size > 0 {
-        // Try to read entries to determine the actual size
-        let mut offset = 0;
-        while offset * ENT_WIDTH < file_size {
-            let position = offset * ENT_WIDTH;
-            if position + ENT_WIDTH > file_size {
-                break;
-            }
-            
-            // Check if this entry is valid by reading the offset
-            let offset_bytes = &mmap[position as usize..(position + OFF_WIDTH) as usize];
-            let offset_value = u32::from_be_bytes(
-                offset_bytes.try_into()
-                    .map_err(|_| Box::new(Error::new(ErrorKind::InvalidData, "Invalid offset bytes")))?,
-            );
-            
-            // If offset is 0 and this is not the first entry, this might be an empty entry
-            if offset_value == 0 && offset > 0 {
-                // Check if the position is also 0
-                let pos_bytes = &mmap[(position + OFF_WIDTH) as usize..(position + ENT_WIDTH) as usize];
-                let pos_value = u64::from_be_bytes(
-                    pos_bytes.try_into()
-                        .map_err(|_| Box::new(Error::new(ErrorKind::InvalidData, "Invalid position bytes")))?,
-                );
-                if pos_value == 0 {
-                    break;
-                }
-            }
-            
-            actual_size = (offset + 1) * ENT_WIDTH;
-            offset += 1;
-        }
-    }
-
-    let index = Index {
-        file: file_obj,
-        path,
-        mmap,
-        size: actual_size,
-    };
-    Ok(index)
+    Ok(Index {
+        file: mapped::open(file, path, MAGIC, "index", ENT_WIDTH, conf)?,
+        interval_bytes: conf.segment.index_interval_bytes,
+    })
 }
 
 impl Index {
     pub fn close(&mut self) -> Result<()> {
-        // Sync all changes to disk
-        self.file.sync_all()?;
-        
-        // Don't truncate the file - keep the full size for the memory map
-        // The actual data size is stored in the file metadata
-        
-        Ok(())
+        self.file.close()
+    }
+
+    // Shrink the file to the entries it holds
+    pub fn trim(&mut self) -> Result<()> {
+        self.file.trim()
+    }
+
+    // Write the mapped entries back to the file
+    pub fn sync(&self) -> Result<()> {
+        self.file.sync()
+    }
+
+    pub fn path(&self) -> &str {
+        &self.file.path
     }
 
     pub fn read(&self, offset: i64) -> Result<(u32, u64)> {
-        if self.size == 0 {
-            return Err(Box::new(Error::new(ErrorKind::UnexpectedEof, "Index is empty")));
+        if self.file.size == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Index is empty").into());
         }
 
-        let index: u32 = if offset == -1 {
+        // Worked out in u64 so an index past 4 GiB still finds its last entry
+        let index: u64 = if offset == -1 {
             // Read the last entry
-            (self.size as u32 / ENT_WIDTH as u32).saturating_sub(1)
+            self.entries().saturating_sub(1)
         } else {
             if offset < 0 {
-                return Err(Box::new(Error::new(ErrorKind::InvalidInput, "Negative offset")));
+                return Err(Error::new(ErrorKind::InvalidInput, "Negative offset").into());
             }
-            offset as u32
+            offset as u64
         };
 
-        let position = (index as u64) * ENT_WIDTH;
-        if self.size < position + ENT_WIDTH {
-            return Err(Box::new(Error::new(
-                ErrorKind::UnexpectedEof,
-                "Index entry out of bounds",
-            )));
+        if index >= self.entries() {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Index entry out of bounds").into());
+        }
+
+        let entry = self.file.entry(index);
+        let mut off_bytes = [0u8; OFF_WIDTH as usize];
+        off_bytes.copy_from_slice(&entry[..OFF_WIDTH as usize]);
+        let mut pos_bytes = [0u8; POS_WIDTH as usize];
+        pos_bytes.copy_from_slice(&entry[OFF_WIDTH as usize..]);
+
+        Ok((u32::from_be_bytes(off_bytes), u64::from_be_bytes(pos_bytes)))
+    }
+
+    // Number of entries currently held in the index
+    pub fn entries(&self) -> u64 {
+        self.file.entries()
+    }
+
+    // The entry for the closest relative offset at or before `off`, which
+    // is where a scan for `off` has to start when the index is sparse
+    pub fn lookup(&self, off: u32) -> Result<(u32, u64)> {
+        // Relative offsets increase along the index, so binary search for the
+        // last entry that is not past the target
+        let (mut low, mut high) = (0, self.entries());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.read(mid as i64)?.0 <= off {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
         }
 
-        // Read offset (u32)
-        let offset_bytes = &self.mmap[position as usize..(position + OFF_WIDTH) as usize];
-        let out = u32::from_be_bytes(
-            offset_bytes.try_into()
-                .map_err(|_| Box::new(Error::new(ErrorKind::InvalidData, "Invalid offset bytes")))?,
-        );
-
-        // Read position (u64)
-        let position_bytes = &self.mmap[(position + OFF_WIDTH) as usize..(position + ENT_WIDTH) as usize];
-        let new_position = u64::from_be_bytes(
-            position_bytes.try_into()
-                .map_err(|_| Box::new(Error::new(ErrorKind::InvalidData, "Invalid position bytes")))?,
-        );
-
-        Ok((out, new_position))
+        if low == 0 {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("No index entry at or before relative offset {}", off),
+            ).into());
+        }
+        self.read(low as i64 - 1)
     }
 
-    pub fn write(&mut self, off: u32, pos: u64) -> Result<()> {
-        // Check if there's enough space in the memory map
-        if (self.mmap.len() as u64) < self.size + ENT_WIDTH {
-            return Err(Box::new(Error::new(
-                ErrorKind::UnexpectedEof,
-                "Index is full",
-            )));
+    // Add an entry for the record at relative offset `off` if one is due.
+    // Every record gets an entry unless the index is sparse, in which case
+    // only the first record and then one at least every `interval_bytes` of
+    // store do. Returns whether an entry was added.
+    pub fn append(&mut self, off: u32, pos: u64) -> Result<bool> {
+        if self.interval_bytes > 0 && self.file.size > 0 {
+            let (_, last_pos) = self.read(-1)?;
+            if pos < last_pos + self.interval_bytes {
+                return Ok(false);
+            }
         }
+        self.write(off, pos)?;
+        Ok(true)
+    }
 
-        // Write offset (u32) in big-endian format
-        let off_bytes = off.to_be_bytes();
-        self.mmap[self.size as usize..(self.size + OFF_WIDTH) as usize].copy_from_slice(&off_bytes);
+    // Drop every entry for a relative offset of `off` or later
+    pub fn truncate(&mut self, off: u32) -> Result<()> {
+        let mut entries = self.entries();
+        while entries > 0 && self.read(entries as i64 - 1)?.0 >= off {
+            entries -= 1;
+        }
 
-        // Write position (u64) in big-endian format
-        let pos_bytes = pos.to_be_bytes();
-        self.mmap[(self.size + OFF_WIDTH) as usize..(self.size + ENT_WIDTH) as usize]
-            .copy_from_slice(&pos_bytes);
+        self.file.truncate(entries)
+    }
 
-        // Update the size
-        self.size += ENT_WIDTH;
+    pub fn write(&mut self, off: u32, pos: u64) -> Result<()> {
+        let mut entry = [0u8; ENT_WIDTH as usize];
+        entry[..OFF_WIDTH as usize].copy_from_slice(&off.to_be_bytes());
+        entry[OFF_WIDTH as usize..].copy_from_slice(&pos.to_be_bytes());
 
-        Ok(())
+        self.file.push(&entry)
     }
 }
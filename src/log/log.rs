@@ -1,25 +1,50 @@
 use byteorder::{BigEndian, ByteOrder};
+use bytes::Bytes;
 use std::fs::File;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::io;
-use std::fs::{self, DirEntry};
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::ops::Range;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
 use std::str::FromStr;
-use super::{config, segment, store};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+use super::error::LogError;
+use super::{compaction, config, format, reader, segment, store};
 
 // Custom Result type for the log operations
-pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+pub type Result<T> = std::result::Result<T, LogError>;
+
+// Locked by whichever process has the log directory open
+const LOCK_FILE: &str = "walrus.lock";
 
 // Abstraction around the Log that will be the entry point for writing to the Log
 // And committing changes
 pub struct Log {
     pub dir: String,
     pub config: config::Config,
-    pub active_segment: Option<segment::Segment>,
-    pub segments: Vec<segment::Segment>,
+    // Every segment keyed by its base offset. The last one is the active
+    // segment that appends go to.
+    pub segments: BTreeMap<u64, segment::Segment>,
+    // Holds the lock on the directory for as long as the log is open
+    _lock: File,
+    // Set while appends are refused for want of disk space
+    read_only: bool,
+    // Bumped on every truncate, so a compaction can tell the log was cut
+    // back while it was working from an unlocked copy of it
+    truncations: u64,
+    // Held by the compaction running on the log, there can only be one
+    compacting: Arc<Mutex<()>>,
 }
 
-pub type SafeLog = Arc<Mutex<Log>>;
+// Reads only need shared access, so any number of them can run alongside
+// each other and alongside a sync. Appends and segment changes take the
+// lock exclusively.
+pub type SafeLog = Arc<RwLock<Log>>;
 
 fn setup_log(dir: String) -> Result<Vec<u64>> {
     // Read through the given directory
@@ -52,6 +77,54 @@ fn setup_log(dir: String) -> Result<Vec<u64>> {
     Ok(base_offsets)
 }
 
+// Take an advisory lock on the directory, failing straight away if another
+// process already has it. The lock goes with the file handle, so it is
+// released however the process exits. The holder's pid is written into the
+// file to help track it down.
+fn lock_dir(dir: &str) -> Result<File> {
+    let path = format!("{}/{}", dir, LOCK_FILE);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)?;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::WouldBlock {
+            return Err(e.into());
+        }
+        let holder = fs::read_to_string(&path).unwrap_or_default();
+        return Err(LogError::DirectoryLocked {
+            dir: dir.to_string(),
+            pid: holder.trim().to_string(),
+            path,
+        });
+    }
+
+    file.set_len(0)?;
+    writeln!(file, "{}", std::process::id())?;
+
+    Ok(file)
+}
+
+// Bytes an unprivileged process can still write to the volume holding `dir`
+fn available_bytes(dir: &str) -> Result<u64> {
+    let path = CString::new(dir).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+// Store bytes a record takes up before compression or encryption
+fn framed_len(record: &segment::Record) -> u64 {
+    (prost::Message::encoded_len(record) + store::FRAME_HEADER_WIDTH) as u64
+}
+
 fn new_log(dir: String, config: config::Config) -> Result<SafeLog> {
     // Set default values if not provided
     let mut config = config;
@@ -63,34 +136,64 @@ fn new_log(dir: String, config: config::Config) -> Result<SafeLog> {
         config.segment.max_index_bytes = 1024;
     }
 
+    if config.segment.max_records_per_segment == 0 {
+        config.segment.max_records_per_segment = segment::MAX_RECORDS_PER_SEGMENT;
+    }
+    if config.segment.max_records_per_segment > segment::MAX_RECORDS_PER_SEGMENT {
+        return Err(LogError::InvalidConfig(format!(
+            "max_records_per_segment is {}, but relative offsets only allow {}",
+            config.segment.max_records_per_segment,
+            segment::MAX_RECORDS_PER_SEGMENT,
+        )));
+    }
+
     // Create the log directory if it doesn't exist
     fs::create_dir_all(&dir)?;
 
+    // Nothing else may write to the directory while we have it open
+    let lock = lock_dir(&dir)?;
+
+    // Settle any compaction that was cut short before looking at segments
+    compaction::recover(&dir)?;
+
     // Setup existing segments
     let base_offsets = setup_log(dir.clone())?;
-    let mut segments = Vec::new();
-    let mut active_segment = None;
+    let mut segments = BTreeMap::new();
 
-    // Load existing segments
+    // Load existing segments, upgrading any written in an older format
     for &offset in &base_offsets {
-        let segment = segment::new(&dir, format!("{}/{}", dir, offset), offset, config.clone())
-            .map_err(|e| e)?;
-        segments.push(segment);
+        format::upgrade_segment(&dir, offset, &config)?;
+        let segment = segment::new(&dir, format!("{}/{}", dir, offset), offset, config.clone())?;
+        segments.insert(offset, segment);
+    }
+
+    // Everything but the last segment is closed for good
+    let sealed = segments.len().saturating_sub(1);
+    for segment in segments.values_mut().take(sealed) {
+        segment.seal()?;
     }
 
-    // Set the last segment as active if segments exist
-    if let Some(mut last_segment) = segments.pop() {
-        active_segment = Some(last_segment);
+    // The last segment becomes the active one. It is the only one that
+    // could have been mid-write when the process went away, so make sure
+    // its tail is intact before handing it out.
+    if let Some(mut last_segment) = segments.last_entry() {
+        last_segment.get_mut().recover()?;
     }
 
-    let log = Log {
+    let mut log = Log {
         dir,
         config,
-        active_segment,
         segments,
+        _lock: lock,
+        read_only: false,
+        truncations: 0,
+        compacting: Arc::new(Mutex::new(())),
     };
 
-    Ok(Arc::new(Mutex::new(log)))
+    // Catch up on anything that expired while the log was closed
+    log.enforce_retention()?;
+
+    Ok(Arc::new(RwLock::new(log)))
 }
 
 impl Log {
@@ -99,79 +202,423 @@ impl Log {
     }
 
     pub fn append(&mut self, record: &mut segment::Record) -> Result<u64> {
-        // If no active segment or current segment is full, create a new one
-        if self.active_segment.is_none() || self.active_segment.as_mut().unwrap().is_maxed() {
+        self.check_space(framed_len(record))?;
+
+        // If no active segment or current segment is full, create a new one.
+        // Rolling closes a segment, which is the point it can become
+        // eligible for retention.
+        if self.needs_new_segment() {
             self.new_segment()?;
+            self.enforce_retention()?;
         }
 
         // Append to the active segment
-        if let Some(ref mut segment) = self.active_segment {
-            return segment.append(record);
+        let segment = self.active_segment().expect("a segment was just created if there was none");
+        let appended = segment.append(record);
+        self.note_disk_full(&appended);
+        appended
+    }
+
+    // Append every record under a single call, assigning them contiguous
+    // offsets. The store of each segment the batch lands in is flushed once,
+    // rolling to a new segment part way through if the batch fills one up.
+    // Returns the range of offsets the records were written at. A batch is
+    // all or nothing: if it fails part way, whatever made it in is cut away
+    // again so no caller is told a record failed that is in the log.
+    pub fn append_batch(&mut self, records: &mut [segment::Record]) -> Result<Range<u64>> {
+        self.check_space(records.iter().map(framed_len).sum())?;
+
+        let start = self.next_offset();
+        if let Err(e) = self.write_batch(records) {
+            if self.next_offset() > start {
+                if let Err(undo) = self.truncate(start) {
+                    warn!("Failed to roll back the batch written at offset {}: {}", start, undo);
+                }
+            }
+            return Err(e);
+        }
+
+        Ok(start..start + records.len() as u64)
+    }
+
+    fn write_batch(&mut self, records: &mut [segment::Record]) -> Result<()> {
+        let mut written = 0;
+
+        while written < records.len() {
+            if self.needs_new_segment() {
+                self.new_segment()?;
+                self.enforce_retention()?;
+            }
+
+            let segment = self.active_segment().expect("a segment was just created if there was none");
+            let appended = segment.append_batch(&mut records[written..]);
+            self.note_disk_full(&appended);
+            written += appended?;
         }
 
-        Err("No active segment available".into())
+        Ok(())
+    }
+
+    // Whether appends are being refused because the disk is short of space
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
     }
 
-    pub fn read(&mut self, offset: u64) -> Result<segment::Record> {
-        // Find the segment that contains this offset
-        for segment in &mut self.segments {
-            if offset >= segment.base_offset() && offset < segment.next_offset() {
-                let segment_result = segment.read(offset);
-                return segment_result;
+    // Make sure `bytes` more can be written without eating into the disk
+    // reserve, going read-only if they cannot and back to accepting writes
+    // once they can. A read-only log checks again even with the guard off,
+    // so that it recovers from running out of space regardless.
+    fn check_space(&mut self, bytes: u64) -> Result<()> {
+        let reserve = self.config.disk.reserve_bytes;
+        if reserve == 0 && !self.read_only {
+            return Ok(());
+        }
+
+        let available = available_bytes(&self.dir)?;
+        if available >= reserve.saturating_add(bytes) {
+            if self.read_only {
+                info!("{} bytes free for log {}, accepting writes again", available, self.dir);
+                self.read_only = false;
             }
+            return Ok(());
         }
 
-        // Check active segment
-        if let Some(ref mut segment) = self.active_segment {
-            if offset >= segment.base_offset() && offset < segment.next_offset() {
-                let segment_result = segment.read(offset);
-                return segment_result;
+        if !self.read_only {
+            warn!(
+                "Only {} bytes free for log {} against a reserve of {}, refusing writes until space is freed",
+                available, self.dir, reserve,
+            );
+            self.read_only = true;
+        }
+        Err(LogError::ReadOnly { available, reserve })
+    }
+
+    // An append that hit a full disk despite the reserve, most likely
+    // because something else filled it, takes the log read-only too
+    fn note_disk_full<T>(&mut self, result: &Result<T>) {
+        if let Err(LogError::DiskFull(_)) = result {
+            warn!("Log {} ran out of disk space, refusing writes until space is freed", self.dir);
+            self.read_only = true;
+        }
+    }
+
+    pub fn read(&self, offset: u64) -> Result<segment::Record> {
+        // The segment holding this offset is the last one starting at or before it
+        match self.segments.range(..=offset).next_back() {
+            Some((_, segment)) if offset < segment.next_offset() => segment.read(offset),
+            _ => Err(self.missing(offset)),
+        }
+    }
+
+    // First offset whose record timestamp is at or after `timestamp`, in
+    // milliseconds since the Unix epoch. None if every record is older.
+    pub fn offset_for_timestamp(&self, timestamp: u64) -> Result<Option<u64>> {
+        for segment in self.segments.values() {
+            if let Some(offset) = segment.offset_for_timestamp(timestamp)? {
+                return Ok(Some(offset));
             }
         }
+        Ok(None)
+    }
 
-        Err("Offset not found in any segment".into())
+    // The encoded record at `offset`, without decoding it. Uncompressed
+    // records in sealed segments come back as slices of the mapped store
+    // rather than copies.
+    pub fn read_bytes(&self, offset: u64) -> Result<Bytes> {
+        match self.segments.range(..=offset).next_back() {
+            Some((_, segment)) if offset < segment.next_offset() => segment.read_bytes(offset),
+            _ => Err(self.missing(offset)),
+        }
     }
 
-    fn new_segment(&mut self) -> Result<()> {
-        let base_offset = if let Some(ref segment) = self.active_segment {
-            segment.next_offset()
-        } else {
-            self.config.segment.initial_offset
-        };
+    // The error for an offset no segment holds. Offsets between the start
+    // and end of the log are ones compaction removed whole segments of.
+    fn missing(&self, offset: u64) -> LogError {
+        let (start, end) = (self.lowest_offset().unwrap_or(self.next_offset()), self.next_offset());
+        if (start..end).contains(&offset) {
+            return LogError::OffsetRemoved(offset);
+        }
+        LogError::OffsetOutOfRange { offset, start, end }
+    }
+
+    // Read records sequentially from `offset` up to the end of the log as it
+    // stands now. Starting at or past the end gives an empty reader.
+    pub fn reader(&self, offset: u64) -> Result<reader::LogReader> {
+        if let Some(lowest) = self.lowest_offset() {
+            if offset < lowest {
+                return Err(LogError::OffsetOutOfRange { offset, start: lowest, end: self.next_offset() });
+            }
+        }
+
+        let mut ranges = Vec::new();
+        for segment in self.segments.values() {
+            if offset >= segment.next_offset() {
+                continue;
+            }
+
+            // Only the segment holding `offset` starts part way through
+            let start = if offset > segment.base_offset() {
+                segment.position(offset)?
+            } else {
+                store::HEADER_WIDTH
+            };
+
+            ranges.push(reader::SegmentRange::open(
+                segment.store_path(),
+                start,
+                segment.size(),
+                segment.next_offset(),
+            )?);
+        }
+
+        Ok(reader::LogReader::new(offset, ranges, self.config.encryption.clone()))
+    }
+
+    // First offset that can be read, or None when the log holds no records
+    pub fn lowest_offset(&self) -> Option<u64> {
+        let (&lowest, _) = self.segments.first_key_value()?;
+        if lowest == self.next_offset() {
+            return None;
+        }
+        Some(lowest)
+    }
 
-        // Move current active segment to segments list
-        if let Some(segment) = self.active_segment.take() {
-            self.segments.push(segment);
+    // Last offset that was written, or None when the log holds no records
+    pub fn highest_offset(&self) -> Option<u64> {
+        self.lowest_offset()?;
+        Some(self.next_offset() - 1)
+    }
+
+    // Offset the next append will be assigned
+    pub fn next_offset(&self) -> u64 {
+        match self.segments.last_key_value() {
+            Some((_, segment)) => segment.next_offset(),
+            None => self.config.segment.initial_offset,
         }
+    }
 
-        // Create new active segment
+    fn active_segment(&mut self) -> Option<&mut segment::Segment> {
+        self.segments.values_mut().next_back()
+    }
+
+    fn needs_new_segment(&mut self) -> bool {
+        match self.active_segment() {
+            Some(segment) => segment.is_maxed(),
+            None => true,
+        }
+    }
+
+    fn new_segment(&mut self) -> Result<()> {
+        let base_offset = self.next_offset();
+
+        // Create the new active segment first, so the current one is left
+        // as it was if that fails
         let new_segment = segment::new(&self.dir, format!("{}/{}", self.dir, base_offset), base_offset, self.config.clone())?;
-        self.active_segment = Some(new_segment);
+
+        // The current active segment will not be written to again, so make
+        // sure it is on disk and serve its reads from a mapping from now on
+        if let Some(segment) = self.active_segment() {
+            segment.sync()?;
+            segment.seal()?;
+        }
+        self.segments.insert(base_offset, new_segment);
         Ok(())
     }
 
-    pub fn close(&mut self) -> Result<()> {
-        // Close active segment
-        if let Some(ref mut segment) = self.active_segment {
-            segment.close()?;
+    // Discard every record at or after `offset`, so the next append is
+    // assigned `offset` again. Segments that start at or after the cut are
+    // deleted outright and the segment containing it is shrunk in place.
+    pub fn truncate(&mut self, offset: u64) -> Result<()> {
+        // Nothing has been assigned at or past the end yet
+        if offset >= self.next_offset() {
+            return Ok(());
         }
 
-        // Close all segments
-        for segment in &mut self.segments {
-            segment.close()?;
+        // Make sure the cut can be made in the segment left holding it before
+        // anything is deleted, so a cut that fails leaves the log as it was
+        if let Some((_, segment)) = self.segments.range(..offset).next_back() {
+            segment.cut_position(offset)?;
+        }
+        self.truncations += 1;
+
+        let removed = self.segments.split_off(&offset);
+        for mut segment in removed.into_values().rev() {
+            info!(
+                "Truncation removing segment {} (offsets {}..{})",
+                segment.base_offset(),
+                segment.base_offset(),
+                segment.next_offset(),
+            );
+            segment.remove()?;
+        }
+
+        // Whatever is now last carries on as the active segment. If the cut
+        // went below every segment, start a fresh one at the cut point.
+        match self.active_segment() {
+            Some(segment) => segment.truncate(offset)?,
+            None => {
+                let segment = segment::new(&self.dir, format!("{}/{}", self.dir, offset), offset, self.config.clone())?;
+                self.segments.insert(offset, segment);
+            }
         }
 
         Ok(())
     }
 
-    pub fn remove(&mut self) -> Result<()> {
-        // Remove active segment
-        if let Some(ref mut segment) = self.active_segment {
+    // Delete the oldest closed segments for as long as any retention policy
+    // says they should go. Returns how many segments were removed. The
+    // lowest readable offset moves up to the base offset of the oldest
+    // segment left.
+    pub fn enforce_retention(&mut self) -> Result<usize> {
+        let retention = self.config.retention.clone();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+
+        let mut total_bytes: u64 = self.segments.values().map(|s| s.size()).sum();
+        let mut removed = 0;
+
+        // The active segment is never removed, only the closed ones before it
+        while self.segments.len() > 1 {
+            let (_, oldest) = self.segments.first_key_value().unwrap();
+            let below_min_offset = retention.min_offset > 0 && oldest.next_offset() <= retention.min_offset;
+            let over_max_bytes = retention.max_bytes > 0 && total_bytes > retention.max_bytes;
+            // Age goes by the newest record in the segment rather than when
+            // its files were written, which compaction resets. A segment left
+            // with no records at all has nothing worth keeping.
+            let over_max_age = retention.max_age_ms > 0
+                && now.saturating_sub(oldest.max_timestamp()) > retention.max_age_ms;
+
+            if !(below_min_offset || over_max_bytes || over_max_age) {
+                break;
+            }
+
+            let (_, mut segment) = self.segments.pop_first().unwrap();
+            total_bytes -= segment.size();
+            info!(
+                "Retention removing segment {} (offsets {}..{})",
+                segment.base_offset(),
+                segment.base_offset(),
+                segment.next_offset(),
+            );
             segment.remove()?;
+            removed += 1;
+        }
+
+        // Whatever freed up space, this is the regular chance to notice it
+        // and start accepting writes again
+        if self.read_only {
+            match self.check_space(0) {
+                Ok(()) | Err(LogError::ReadOnly { .. }) => {}
+                Err(e) => return Err(e),
+            }
         }
 
+        Ok(removed)
+    }
+
+    // Rewrite the closed segments so only the latest record for each key is
+    // left in them, dropping tombstones once they are older than the
+    // tombstone retention. Records without a key are never removed. The
+    // records that are left keep their offsets, so reads of removed offsets
+    // fail and readers skip past them. Returns how many records were removed.
+    //
+    // Only the reading and writing of records happens with the log unlocked,
+    // working from files opened under a read lock, so appends and reads carry
+    // on meanwhile. The write lock is taken just to swap each rewritten
+    // segment in. This blocks on disk I/O, so call it off the async runtime.
+    pub fn compact(log: &SafeLog) -> Result<usize> {
+        let compacting = log.read().unwrap().compacting.clone();
+        let _running = compacting.lock().unwrap();
+
+        let (reader, closed, truncations, dir, config) = {
+            let this = log.read().unwrap();
+            let lowest = match this.lowest_offset() {
+                Some(lowest) if this.segments.len() > 1 => lowest,
+                _ => return Ok(0),
+            };
+            // The active segment is left alone, only the closed ones before it
+            let closed: Vec<u64> = this.segments.keys().copied().take(this.segments.len() - 1).collect();
+            (this.reader(lowest)?, closed, this.truncations, this.dir.clone(), this.config.clone())
+        };
+        let cleaner = compaction::Cleaner::new(reader, &config)?;
+        let mut removed = 0;
+
+        for base_offset in closed {
+            let (counted, records, next_offset) = {
+                let this = log.read().unwrap();
+                // Retention may have got to the segment first
+                let Some(segment) = this.segments.get(&base_offset) else {
+                    continue;
+                };
+                (cleaner.records(segment)?, cleaner.records(segment)?, segment.next_offset())
+            };
+            let (kept, dropped) = cleaner.count(counted)?;
+            if dropped == 0 {
+                continue;
+            }
+            if kept > 0 {
+                cleaner.rewrite(&dir, base_offset, records, &config)?;
+            }
+
+            let mut this = log.write().unwrap();
+            // A truncate invalidates what the cleaner knows about the latest
+            // record for each key, and a segment that was removed or cut back
+            // no longer matches its rewrite
+            let truncated = this.truncations != truncations;
+            if truncated || this.segments.get(&base_offset).map(|s| s.next_offset()) != Some(next_offset) {
+                if kept > 0 {
+                    compaction::discard(&dir)?;
+                }
+                if truncated {
+                    break;
+                }
+                continue;
+            }
+
+            info!(
+                "Compaction removing {} of {} records from segment {}",
+                dropped,
+                kept + dropped,
+                base_offset,
+            );
+            removed += dropped;
+
+            if kept == 0 {
+                let mut segment = this.segments.remove(&base_offset).unwrap();
+                segment.remove()?;
+                continue;
+            }
+
+            compaction::swap(&dir)?;
+            let mut segment = segment::new(&dir, format!("{}/{}", dir, base_offset), base_offset, config.clone())?;
+            segment.seal()?;
+            this.segments.insert(base_offset, segment);
+        }
+
+        Ok(removed)
+    }
+
+    // Force everything appended so far out to disk, whatever the
+    // durability mode. Closed segments were synced when they were rolled.
+    pub fn sync(&self) -> Result<()> {
+        if let Some((_, segment)) = self.segments.last_key_value() {
+            segment.sync()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn close(&mut self) -> Result<()> {
+        // Close all segments
+        for segment in self.segments.values_mut() {
+            segment.close()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn remove(&mut self) -> Result<()> {
         // Remove all segments
-        for segment in &mut self.segments {
+        for segment in self.segments.values_mut() {
             segment.remove()?;
         }
 
@@ -0,0 +1,185 @@
+use crate::log::error::LogError;
+use crate::log::{config, format};
+use memmap2::MmapMut;
+use std::fs::File;
+
+// Custom Result type to match other modules
+pub type Result<T> = std::result::Result<T, LogError>;
+
+// Both index files are a fixed header laid out as
+// [magic: 4 bytes][version: u32][entries: u64], followed by fixed width
+// entries. The version is the segment format version from `format`.
+// Keeping the entry count in the header means opening an index does not
+// have to go looking for where the valid entries end. This holds the
+// header and the memory map, and the index types lay out their entries.
+const MAGIC_WIDTH: u64 = 4;
+const VERSION_WIDTH: u64 = 4;
+const COUNT_WIDTH: u64 = 8;
+pub const HEADER_WIDTH: u64 = MAGIC_WIDTH + VERSION_WIDTH + COUNT_WIDTH;
+
+pub struct MappedFile {
+    pub file: File,
+    pub path: String,
+    mmap: MmapMut,
+    // Bytes of entries, not counting the header
+    pub size: u64,
+    entry_width: u64,
+}
+
+// Map the index file at `path`, writing a header with `magic` if it is new.
+// `kind` names the type of index in errors.
+pub fn open(
+    file: &File,
+    path: String,
+    magic: &[u8; 4],
+    kind: &str,
+    entry_width: u64,
+    conf: &config::Config,
+) -> Result<MappedFile> {
+    let file_size = file.metadata()?.len();
+    let file_obj = file.try_clone()?;
+
+    // Start with room for the configured size up front. The file grows
+    // past that as entries are added, and is trimmed back when closed.
+    let initial_size = conf.segment.max_index_bytes.max(HEADER_WIDTH);
+    if file_size < initial_size {
+        file_obj.set_len(initial_size)?;
+    }
+
+    let mut mmap = unsafe { MmapMut::map_mut(&file_obj)? };
+
+    // A file that is brand new, or that was created but never had its header
+    // written, starts out empty
+    let header = &mmap[..HEADER_WIDTH as usize];
+    if file_size < HEADER_WIDTH || header.iter().all(|&b| b == 0) {
+        mmap[..MAGIC_WIDTH as usize].copy_from_slice(magic);
+        mmap[MAGIC_WIDTH as usize..(MAGIC_WIDTH + VERSION_WIDTH) as usize]
+            .copy_from_slice(&format::VERSION.to_be_bytes());
+        mmap[(MAGIC_WIDTH + VERSION_WIDTH) as usize..HEADER_WIDTH as usize]
+            .copy_from_slice(&0u64.to_be_bytes());
+        mmap.flush()?;
+    }
+
+    if &mmap[..MAGIC_WIDTH as usize] != magic {
+        return Err(LogError::InvalidFormat(format!("{} is not a walrus {} file", path, kind)));
+    }
+
+    let mut version = [0u8; VERSION_WIDTH as usize];
+    version.copy_from_slice(&mmap[MAGIC_WIDTH as usize..(MAGIC_WIDTH + VERSION_WIDTH) as usize]);
+    let version = u32::from_be_bytes(version);
+    if version != format::VERSION {
+        return Err(LogError::InvalidFormat(format!(
+            "{} has {} format version {}, expected {}", path, kind, version, format::VERSION)));
+    }
+
+    let mut entries = [0u8; COUNT_WIDTH as usize];
+    entries.copy_from_slice(&mmap[(MAGIC_WIDTH + VERSION_WIDTH) as usize..HEADER_WIDTH as usize]);
+    let entries = u64::from_be_bytes(entries);
+    let size = entries.checked_mul(entry_width).filter(|&size| HEADER_WIDTH + size <= mmap.len() as u64);
+    let Some(size) = size else {
+        return Err(LogError::InvalidFormat(format!(
+            "{} claims {} entries but only has room for fewer", path, entries)));
+    };
+
+    Ok(MappedFile {
+        file: file_obj,
+        path,
+        mmap,
+        size,
+        entry_width,
+    })
+}
+
+impl MappedFile {
+    pub fn close(&mut self) -> Result<()> {
+        self.trim()?;
+        self.file.sync_all()?;
+
+        Ok(())
+    }
+
+    // Give back the room that was never used. The mapping has to shrink
+    // with the file, it cannot be left covering past the end of it. The file
+    // grows again if more entries are added after all.
+    pub fn trim(&mut self) -> Result<()> {
+        self.mmap.flush()?;
+        if self.mmap.len() as u64 > HEADER_WIDTH + self.size {
+            self.resize(HEADER_WIDTH + self.size)?;
+        }
+
+        Ok(())
+    }
+
+    // Write the mapped entries back to the file
+    pub fn sync(&self) -> Result<()> {
+        self.mmap.flush()?;
+
+        Ok(())
+    }
+
+    // Number of entries currently held
+    pub fn entries(&self) -> u64 {
+        self.size / self.entry_width
+    }
+
+    // The bytes of the entry at position `i`, which has to be below `entries`
+    pub fn entry(&self, i: u64) -> &[u8] {
+        let position = (HEADER_WIDTH + i * self.entry_width) as usize;
+        &self.mmap[position..position + self.entry_width as usize]
+    }
+
+    // Add an entry after the last one, growing the file if it is out of room
+    pub fn push(&mut self, entry: &[u8]) -> Result<()> {
+        // Out of room in the memory map, so double the file to make more
+        if self.is_full() {
+            self.mmap.flush()?;
+            self.resize((self.mmap.len() as u64 * 2).max(HEADER_WIDTH + self.size + self.entry_width))?;
+        }
+
+        let position = (HEADER_WIDTH + self.size) as usize;
+        self.mmap[position..position + self.entry_width as usize].copy_from_slice(entry);
+
+        // Update the size, only publishing the new entry count in the header
+        // once the entry itself is in place
+        self.size += self.entry_width;
+        self.write_count();
+
+        Ok(())
+    }
+
+    // Keep only the first `entries` entries. The freed space is zeroed so
+    // stale entries never get mistaken for live ones.
+    pub fn truncate(&mut self, entries: u64) -> Result<()> {
+        let new_size = entries * self.entry_width;
+        if new_size >= self.size {
+            return Ok(());
+        }
+
+        let start = HEADER_WIDTH + new_size;
+        let end = HEADER_WIDTH + self.size;
+        self.mmap[start as usize..end as usize].fill(0);
+        self.size = new_size;
+        self.write_count();
+        self.mmap.flush()?;
+
+        Ok(())
+    }
+
+    // Whether another entry would still fit in the memory map
+    fn is_full(&self) -> bool {
+        (self.mmap.len() as u64) < HEADER_WIDTH + self.size + self.entry_width
+    }
+
+    // Set the file to `len` bytes and map all of it again
+    fn resize(&mut self, len: u64) -> Result<()> {
+        self.file.set_len(len)?;
+        self.mmap = unsafe { MmapMut::map_mut(&self.file)? };
+
+        Ok(())
+    }
+
+    fn write_count(&mut self) {
+        let count = self.entries().to_be_bytes();
+        self.mmap[(MAGIC_WIDTH + VERSION_WIDTH) as usize..HEADER_WIDTH as usize].copy_from_slice(&count);
+    }
+}
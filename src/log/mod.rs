@@ -1,5 +1,13 @@
+pub mod commit;
+pub mod compaction;
 pub mod config;
+pub mod encryption;
+pub mod error;
+pub mod format;
 pub mod index;
+pub mod mapped;
+pub mod reader;
 pub mod segment;
 pub mod store;
+pub mod timeindex;
 pub mod log;
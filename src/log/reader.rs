@@ -0,0 +1,166 @@
+use super::segment::Record;
+use super::encryption::KeyRing;
+use super::error::LogError;
+use super::store;
+use futures::Stream;
+use prost::Message;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufReader, Seek, SeekFrom};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+
+// Custom Result type to match other modules
+pub type Result<T> = std::result::Result<T, LogError>;
+
+const READ_BUFFER_CAPACITY: usize = 64 * 1024;
+const STREAM_CHANNEL_CAPACITY: usize = 1024;
+
+// The stretch of a segment's store a reader has to get through, captured
+// when the reader is created. The store is opened there and then, so the
+// reader keeps reading the same file even if retention deletes it or
+// compaction swaps in a rewritten one while it is partway through the log.
+pub(crate) struct SegmentRange {
+    file: File,
+    path: String,
+    start: u64,
+    end: u64,
+    next_offset: u64,
+}
+
+impl SegmentRange {
+    pub fn open(path: String, start: u64, end: u64, next_offset: u64) -> Result<Self> {
+        Ok(Self {
+            file: File::open(&path)?,
+            path,
+            start,
+            end,
+            next_offset,
+        })
+    }
+}
+
+// A store file being read front to back
+struct OpenSegment {
+    reader: BufReader<File>,
+    path: String,
+    pos: u64,
+    end: u64,
+    next_offset: u64,
+}
+
+// Reads records in offset order from a starting offset, walking the segments
+// one after another and reading each store sequentially through its own file
+// handle rather than doing an index lookup and seek per record. The reader
+// covers the records that were in the log when it was created. Iteration
+// stops after the first error.
+pub struct LogReader {
+    pending: VecDeque<SegmentRange>,
+    current: Option<OpenSegment>,
+    offset: u64,
+    failed: bool,
+    keys: Option<Arc<KeyRing>>,
+}
+
+impl LogReader {
+    pub(crate) fn new(offset: u64, ranges: Vec<SegmentRange>, keys: Option<Arc<KeyRing>>) -> Self {
+        Self {
+            pending: ranges.into(),
+            current: None,
+            offset,
+            failed: false,
+            keys,
+        }
+    }
+
+    // Offset of the next record the reader will return
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    // Hand the reader to a blocking task and receive its records as a
+    // `Stream`. Must be called from within a tokio runtime.
+    pub fn into_stream(self) -> LogStream {
+        let (sender, receiver) = mpsc::channel(STREAM_CHANNEL_CAPACITY);
+
+        tokio::task::spawn_blocking(move || {
+            for result in self {
+                // The stream was dropped, nobody is listening any more
+                if sender.blocking_send(result).is_err() {
+                    break;
+                }
+            }
+        });
+
+        LogStream { receiver }
+    }
+
+    fn open(range: SegmentRange) -> Result<OpenSegment> {
+        let mut file = range.file;
+        file.seek(SeekFrom::Start(range.start))?;
+
+        Ok(OpenSegment {
+            reader: BufReader::with_capacity(READ_BUFFER_CAPACITY, file),
+            path: range.path,
+            pos: range.start,
+            end: range.end,
+            next_offset: range.next_offset,
+        })
+    }
+
+    fn read_next(segment: &mut OpenSegment, keys: Option<&KeyRing>) -> Result<Record> {
+        let (bytes, next) = store::read_frame(&mut segment.reader, &segment.path, segment.pos, segment.end, keys)?;
+        segment.pos = next;
+
+        let record = Record::decode(&*bytes)?;
+        Ok(record)
+    }
+}
+
+impl Iterator for LogReader {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        loop {
+            if let Some(segment) = self.current.as_mut() {
+                if self.offset < segment.next_offset {
+                    let result = Self::read_next(segment, self.keys.as_deref());
+                    match result {
+                        Ok(ref record) => self.offset = record.offset + 1,
+                        Err(_) => self.failed = true,
+                    }
+                    return Some(result);
+                }
+                self.current = None;
+            }
+
+            let range = self.pending.pop_front()?;
+            match Self::open(range) {
+                Ok(segment) => self.current = Some(segment),
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+// Async counterpart of `LogReader`, fed by a blocking task reading ahead
+pub struct LogStream {
+    receiver: mpsc::Receiver<Result<Record>>,
+}
+
+impl Stream for LogStream {
+    type Item = Result<Record>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
@@ -1,16 +1,43 @@
-use super::{config, index, store};
+use super::error::LogError;
+use super::{config, index, store, timeindex};
+use bytes::Bytes;
+use memmap2::Mmap;
 use prost::Message;
-use std::fs::{OpenOptions, remove_file};
+use std::fs::{self, File, OpenOptions, remove_file};
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
 
 // Custom Result type to match log.rs
-pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+pub type Result<T> = std::result::Result<T, LogError>;
 
 // Include the generated Record type
-include!(concat!(env!("OUT_DIR"), "/log.rs"));
+include!(concat!(env!("OUT_DIR"), "/log/log.rs"));
+
+// The indexes store offsets relative to the base offset as u32, which caps
+// how many records one segment can hold
+pub const MAX_RECORDS_PER_SEGMENT: u64 = u32::MAX as u64 + 1;
 
 pub struct Segment {
     store: store::SafeStore,
     index: index::Index,
+    timeindex: timeindex::TimeIndex,
+    // Highest record timestamp in the segment
+    max_timestamp: u64,
+    // Separate handle for reads, which use positional I/O and so never
+    // have to wait on the store lock held by writes and syncs
+    file: File,
+    store_path: String,
+    // Bytes of the store that have been handed to the OS and can be read
+    store_size: u64,
+    // Read-only mapping of the whole store once the segment is sealed and
+    // will not be written again. Reads hand out slices of it.
+    sealed: Option<Bytes>,
+    // Whether the current store file has ever been mapped. Slices of the
+    // mapping can outlive the segment being unsealed, so the file must never
+    // shrink under them.
+    mapped: bool,
     base_offset: u64,
     next_offset: u64,
     config: config::Config,
@@ -19,15 +46,12 @@ pub struct Segment {
 pub fn new(dir: &str, _path: String, base_off: u64, conf: config::Config) -> Result<Segment> {
     let store_path = format!("{}/{}.store", dir, base_off);
     let index_path = format!("{}/{}.index", dir, base_off);
+    let timeindex_path = format!("{}/{}.timeindex", dir, base_off);
 
-    let store_file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .append(true)
-        .open(&store_path)?;
+    let store_file = open_store(&store_path)?;
 
-    let store = store::new(&store_file.try_clone()?, store_path)?;
+    let store = store::new(&store_file.try_clone()?, store_path.clone(), &conf)?;
+    let store_size = store.lock().unwrap().size;
 
     let index_file = OpenOptions::new()
         .read(true)
@@ -37,27 +61,62 @@ pub fn new(dir: &str, _path: String, base_off: u64, conf: config::Config) -> Res
 
     let index = index::new(&index_file.try_clone()?, index_path, &conf)?;
 
-    // Calculate next_offset by reading the last entry in the index
-    // If index is empty, next_offset = base_offset
-    // If index has entries, next_offset = base_offset + last_relative_offset + 1
-    let next_offset = match index.read(-1) {
-        Ok((relative_offset, _)) => {
-            // The relative offset is 0-based, so we add 1 to get the next offset
-            base_off + relative_offset as u64 + 1
-        },
-        Err(_) => {
-            // Index is empty, start at base_offset
-            base_off
-        },
-    };
+    let timeindex_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&timeindex_path)?;
 
-    Ok(Segment {
+    let timeindex = timeindex::new(&timeindex_file, timeindex_path, &conf)?;
+
+    let mut segment = Segment {
         store,
         index,
+        timeindex,
+        max_timestamp: 0,
+        file: store_file,
+        store_path,
+        store_size,
+        sealed: None,
+        mapped: false,
         base_offset: base_off,
-        next_offset,
+        next_offset: base_off,
         config: conf,
-    })
+    };
+
+    // The index may not have an entry for every record, so the last record
+    // is found by scanning on from the last entry
+    let (next_offset, _, max_timestamp) = segment.find_tail()?;
+    segment.next_offset = next_offset;
+    segment.max_timestamp = max_timestamp;
+
+    // Segments written before there were time indexes get theirs built from
+    // the timestamps already in their records
+    if segment.timeindex.entries() == 0 && segment.next_offset > segment.base_offset {
+        segment.rebuild_time_index()?;
+    }
+
+    Ok(segment)
+}
+
+fn open_store(path: &str) -> Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .append(true)
+        .open(path)?;
+
+    Ok(file)
+}
+
+// Records keep the timestamp their producer gave them, and are stamped with
+// the time they were appended otherwise
+fn stamp(record: &mut Record) {
+    if record.timestamp == 0 {
+        record.timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+    }
 }
 
 impl Segment {
@@ -70,63 +129,534 @@ impl Segment {
         self.next_offset
     }
 
+    // Offsets taken up by the segment so far, including any that compaction
+    // has since removed
+    pub fn records(&self) -> u64 {
+        self.next_offset - self.base_offset
+    }
+
+    fn max_records(&self) -> u64 {
+        match self.config.segment.max_records_per_segment {
+            0 => MAX_RECORDS_PER_SEGMENT,
+            max => max.min(MAX_RECORDS_PER_SEGMENT),
+        }
+    }
+
+    // `offset` relative to the base offset, as the indexes store it. Refuses
+    // offsets that would not fit rather than letting them wrap around.
+    fn relative_offset(&self, offset: u64) -> Result<u32> {
+        if offset - self.base_offset >= self.max_records() {
+            return Err(LogError::SegmentFull {
+                base_offset: self.base_offset,
+                max_records: self.max_records(),
+            });
+        }
+        Ok((offset - self.base_offset) as u32)
+    }
+
+    // Run an append, putting the segment back the way it was if it fails
+    // so that none of it is left behind: no frames in the store, no index
+    // entries and no offsets used up. Retrying then lands the record at the
+    // same offset rather than after a stray copy of it.
+    fn rolling_back<T>(&mut self, append: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        let (next_offset, store_size, max_timestamp) = (self.next_offset, self.store_size, self.max_timestamp);
+
+        let result = append(self);
+        if result.is_err() {
+            if let Err(e) = self.roll_back(next_offset, store_size) {
+                warn!("Failed to roll back segment {} after a failed append: {}", self.base_offset, e);
+            }
+            self.next_offset = next_offset;
+            self.store_size = store_size;
+            self.max_timestamp = max_timestamp;
+        }
+        result
+    }
+
+    fn roll_back(&mut self, next_offset: u64, store_size: u64) -> Result<()> {
+        self.store.lock().unwrap().rollback(store_size)?;
+
+        // A full segment has no relative offset left to cut at, and nothing
+        // was written to it
+        if let Ok(relative_offset) = u32::try_from(next_offset - self.base_offset) {
+            self.index.truncate(relative_offset)?;
+            self.timeindex.truncate(relative_offset)?;
+        }
+        Ok(())
+    }
+
     pub fn append(&mut self, record: &mut Record) -> Result<u64> {
+        self.rolling_back(|segment| segment.write_record(record))
+    }
+
+    fn write_record(&mut self, record: &mut Record) -> Result<u64> {
         // Set the record's offset to the current next_offset
         let current_offset = self.next_offset;
         record.offset = current_offset;
+        stamp(record);
         
+        // Calculate relative offset for index. A full segment has to be
+        // caught before anything is written, or the frame would be left in
+        // the store without an offset.
+        let relative_offset = self.relative_offset(self.next_offset)?;
+
         // Convert the record to bytes
         let bytes = record.encode_to_vec();
 
         // Append to store and get the position
         let mut safe_store = self.store.lock().unwrap();
-        let (_, position) = safe_store.append(&bytes)?;
-
-        // Calculate relative offset for index
-        let relative_offset = (self.next_offset - self.base_offset) as u32;
+        let (_, position) = safe_store.write(&bytes)?;
 
-        // Write to index
-        self.index.write(relative_offset, position)?;
+        // Write to indexes
+        self.max_timestamp = self.max_timestamp.max(record.timestamp);
+        if self.index.append(relative_offset, position)? {
+            self.timeindex.append(self.max_timestamp, relative_offset)?;
+        }
         
         // Increment next_offset
         self.next_offset += 1;
 
+        // Only once both halves are written do they go out to disk
+        if safe_store.commit()? {
+            self.index.sync()?;
+            self.timeindex.sync()?;
+        }
+        self.store_size = safe_store.size;
+
         Ok(record.offset)
     }
 
-    pub fn read(&mut self, offset: u64) -> Result<Record> {
+    // Append records from the front of `records` until they run out or the
+    // segment fills up, committing the store once at the end. Returns how many
+    // were written, which is always at least one when there is anything to
+    // write, the same way `append` can take a segment past its limits.
+    pub fn append_batch(&mut self, records: &mut [Record]) -> Result<usize> {
+        self.rolling_back(|segment| segment.write_batch(records))
+    }
+
+    fn write_batch(&mut self, records: &mut [Record]) -> Result<usize> {
+        let mut safe_store = self.store.lock().unwrap();
+        let mut written = 0;
+
+        for record in records.iter_mut() {
+            // Going past the record limit would overflow the relative offset,
+            // so unlike the store size it is never exceeded
+            if self.records() >= self.max_records()
+                || (written > 0 && safe_store.size >= self.config.segment.max_store_bytes)
+            {
+                break;
+            }
+
+            record.offset = self.next_offset;
+            stamp(record);
+            let relative_offset = self.relative_offset(self.next_offset)?;
+            let (_, position) = safe_store.write(&record.encode_to_vec())?;
+
+            self.max_timestamp = self.max_timestamp.max(record.timestamp);
+            if self.index.append(relative_offset, position)? {
+                self.timeindex.append(self.max_timestamp, relative_offset)?;
+            }
+
+            self.next_offset += 1;
+            written += 1;
+        }
+
+        if written > 0 && safe_store.commit()? {
+            self.index.sync()?;
+            self.timeindex.sync()?;
+        }
+        self.store_size = safe_store.size;
+
+        Ok(written)
+    }
+
+    // Append a record at the offset it already has, which may be past the
+    // next one and so leave a gap. Compaction copies the records it keeps
+    // into the rewritten segment this way.
+    pub fn append_existing(&mut self, record: &Record) -> Result<()> {
+        self.rolling_back(|segment| segment.write_existing(record))
+    }
+
+    fn write_existing(&mut self, record: &Record) -> Result<()> {
+        if record.offset < self.next_offset {
+            return Err(LogError::OffsetOutOfOrder {
+                offset: record.offset,
+                base_offset: self.base_offset,
+                next_offset: self.next_offset,
+            });
+        }
+
+        let relative_offset = self.relative_offset(record.offset)?;
+
+        let mut safe_store = self.store.lock().unwrap();
+        let (_, position) = safe_store.write(&record.encode_to_vec())?;
+
+        self.max_timestamp = self.max_timestamp.max(record.timestamp);
+        if self.index.append(relative_offset, position)? {
+            self.timeindex.append(self.max_timestamp, relative_offset)?;
+        }
+
+        self.next_offset = record.offset + 1;
+
+        if safe_store.commit()? {
+            self.index.sync()?;
+            self.timeindex.sync()?;
+        }
+        self.store_size = safe_store.size;
+
+        Ok(())
+    }
+
+    fn rebuild_time_index(&mut self) -> Result<()> {
+        let mut max_timestamp = 0;
+        let mut position = store::HEADER_WIDTH;
+        while position < self.store_size {
+            let (bytes, next) = self.frame_at(position)?;
+            let record = Record::decode(bytes.clone())?;
+            max_timestamp = max_timestamp.max(record.timestamp);
+
+            // Only records with an offset index entry get a time index entry
+            let relative_offset = (record.offset - self.base_offset) as u32;
+            if self.index.lookup(relative_offset)?.0 == relative_offset {
+                self.timeindex.append(max_timestamp, relative_offset)?;
+            }
+
+            position = next;
+        }
+        self.timeindex.sync()
+    }
+
+    // First offset in the segment whose record timestamp is at or after
+    // `timestamp`, or None if every record is older
+    pub fn offset_for_timestamp(&self, timestamp: u64) -> Result<Option<u64>> {
+        if self.next_offset == self.base_offset || timestamp > self.max_timestamp {
+            return Ok(None);
+        }
+
+        // Scan forward from the last point the time index knows is too early
+        let start = self.base_offset + self.timeindex.lookup(timestamp).unwrap_or(0) as u64;
+        let mut position = self.position(start)?;
+        while position < self.store_size {
+            let (bytes, next) = self.frame_at(position)?;
+            let record = Record::decode(bytes.clone())?;
+            if record.timestamp >= timestamp {
+                return Ok(Some(record.offset));
+            }
+            position = next;
+        }
+
+        Ok(None)
+    }
+
+    pub fn read(&self, offset: u64) -> Result<Record> {
+        // Decode the record
+        let record = Record::decode(self.read_bytes(offset)?)?;
+        Ok(record)
+    }
+
+    // The encoded record at `offset`. For a sealed segment this is a slice of
+    // the mapped store unless the record was compressed, so no copy is made.
+    pub fn read_bytes(&self, offset: u64) -> Result<Bytes> {
+        let (_, bytes) = self.find(offset)?;
+        Ok(bytes)
+    }
+
+    // Locate the frame holding `offset`, returning where it starts along with
+    // its payload
+    fn find(&self, offset: u64) -> Result<(u64, Bytes)> {
+        match self.seek(offset)? {
+            (found, position, bytes) if found == offset => Ok((position, bytes)),
+            _ => Err(LogError::OffsetRemoved(offset)),
+        }
+    }
+
+    // Locate the first frame holding `offset` or a later one, which is a
+    // later one when compaction has removed `offset`. Returns the offset
+    // found, where its frame starts and its payload. Starts from the nearest
+    // index entry at or before the offset and scans forward from there if
+    // the entry is for an earlier one.
+    fn seek(&self, offset: u64) -> Result<(u64, u64, Bytes)> {
         // Validate offset is within this segment's range
         if offset < self.base_offset || offset >= self.next_offset {
-            return Err(format!("Offset {} not found in segment (base: {}, next: {})", 
-                             offset, self.base_offset, self.next_offset).into());
+            return Err(self.out_of_range(offset));
         }
 
         // Calculate relative offset for index lookup
-        let relative_offset = (offset - self.base_offset) as i64;
+        let relative_offset = (offset - self.base_offset) as u32;
         
-        // Read from index to get position
-        let (_, position) = self.index.read(relative_offset)?;
+        // Read from index to get position. The first record always has an
+        // entry, so there is only none this early when compaction removed
+        // the records at the start of the segment.
+        let mut position = match self.index.lookup(relative_offset) {
+            Ok((entry_offset, position)) if entry_offset == relative_offset => {
+                return Ok((offset, position, self.frame_at(position)?.0));
+            }
+            Ok((_, position)) => position,
+            Err(_) => store::HEADER_WIDTH,
+        };
+
+        while position < self.store_size {
+            let (bytes, next) = self.frame_at(position)?;
+            let record_offset = Record::decode(bytes.clone())?.offset;
+            if record_offset >= offset {
+                return Ok((record_offset, position, bytes));
+            }
+            position = next;
+        }
+
+        Err(LogError::OffsetRemoved(offset))
+    }
+
+    fn out_of_range(&self, offset: u64) -> LogError {
+        LogError::OffsetOutOfRange {
+            offset,
+            start: self.base_offset,
+            end: self.next_offset,
+        }
+    }
+
+    // Read the frame starting at `position` in the store, returning its
+    // payload and where the next frame starts
+    fn frame_at(&self, position: u64) -> Result<(Bytes, u64)> {
+        match self.sealed {
+            Some(ref data) => store::frame_in(data, &self.store_path, position, self.config.encryption.as_deref()),
+            None => {
+                let (payload, next) = store::read_frame_at(
+                    &self.file,
+                    &self.store_path,
+                    position,
+                    self.store_size,
+                    self.config.encryption.as_deref(),
+                )?;
+                Ok((payload.into(), next))
+            }
+        }
+    }
+
+    // Work out where the intact records in the store end, returning the
+    // offset after the last one, the position just past its frame and the
+    // highest timestamp among them. Starts from the last index entry that
+    // points at an intact frame and scans on from there, stopping at the
+    // first frame that is torn or corrupt.
+    fn find_tail(&self) -> Result<(u64, u64, u64)> {
+        let mut entries = self.index.entries();
+        let (mut next_offset, mut position) = (self.base_offset, store::HEADER_WIDTH);
+        let mut max_timestamp = 0;
+
+        // Walk back from the last index entry until one points at an intact frame
+        while entries > 0 {
+            let (relative_offset, entry_position) = self.index.read(entries as i64 - 1)?;
+            match self.frame_at(entry_position) {
+                Ok(_) => {
+                    next_offset = self.base_offset + relative_offset as u64;
+                    position = entry_position;
+
+                    // Time index entries are only made at offset index entries,
+                    // so the latest one up to here covers every earlier record
+                    max_timestamp = self.timeindex.last_at(relative_offset).unwrap_or(0);
+                    break;
+                }
+                Err(LogError::Corrupt(_)) => entries -= 1,
+                Err(e) => return Err(e),
+            }
+        }
+
+        while position < self.store_size {
+            let (bytes, next) = match self.frame_at(position) {
+                Ok(frame) => frame,
+                Err(LogError::Corrupt(_)) => break,
+                Err(e) => return Err(e),
+            };
+            let record = match Record::decode(bytes.clone()) {
+                Ok(record) => record,
+                Err(_) => break,
+            };
+
+            next_offset = record.offset + 1;
+            max_timestamp = max_timestamp.max(record.timestamp);
+            position = next;
+        }
+
+        Ok((next_offset, position, max_timestamp))
+    }
+
+    // Map the store read-only now that nothing more will be appended to it,
+    // and trim the indexes back from the room they were holding for more
+    pub fn seal(&mut self) -> Result<()> {
+        self.index.trim()?;
+        self.timeindex.trim()?;
+
+        let mmap = unsafe { Mmap::map(&self.file)? };
+        self.sealed = Some(Bytes::from_owner(mmap));
+        self.mapped = true;
+        Ok(())
+    }
+
+    // Validate the tail of the segment after an unclean shutdown. A crash
+    // mid-append can leave a partial frame at the end of the store, or index
+    // entries pointing at data that never made it out of the write buffer.
+    // Both files are cut back to the last record that was fully written.
+    pub fn recover(&mut self) -> Result<()> {
+        let (next_offset, valid_end, max_timestamp) = self.find_tail()?;
+        self.max_timestamp = max_timestamp;
+
+        // Neither index may point at a record that did not survive. A
+        // segment filled to its last relative offset has nothing past it.
+        let entries = self.index.entries();
+        if let Ok(valid_offset) = u32::try_from(next_offset - self.base_offset) {
+            self.index.truncate(valid_offset)?;
+            self.timeindex.truncate(valid_offset)?;
+        }
 
-        // Read from store
         let mut safe_store = self.store.lock().unwrap();
-        let bytes = safe_store.read(position)?;
+        let discarded_bytes = safe_store.size - valid_end;
+        let discarded_entries = entries - self.index.entries();
+        if discarded_bytes == 0 && discarded_entries == 0 {
+            return Ok(());
+        }
 
-        // Decode the record
-        let record = Record::decode(&*bytes)?;
-        Ok(record)
+        warn!(
+            "Recovering segment {}: discarding {} index entries and {} store bytes after offset {}",
+            self.base_offset,
+            discarded_entries,
+            discarded_bytes,
+            next_offset,
+        );
+
+        safe_store.truncate(valid_end)?;
+        self.next_offset = next_offset;
+        self.store_size = valid_end;
+
+        Ok(())
+    }
+
+    // Where the store has to be cut to remove every record from `offset`
+    // onwards, or None when there is nothing from there on to remove. If
+    // compaction removed `offset`, that is where the next record left starts.
+    pub fn cut_position(&self, offset: u64) -> Result<Option<u64>> {
+        if offset < self.base_offset {
+            return Err(self.out_of_range(offset));
+        }
+        if offset >= self.next_offset {
+            return Ok(None);
+        }
+
+        Ok(Some(self.position(offset)?))
+    }
+
+    // Remove every record from `offset` onwards, so the next append reuses
+    // `offset`. Past the last record nothing is removed, but the next append
+    // still gets `offset`, as compaction may have removed the records that
+    // had the offsets in between.
+    pub fn truncate(&mut self, offset: u64) -> Result<()> {
+        let cut = self.cut_position(offset)?;
+
+        // The segment is going to be appended to again. Slices of a sealed
+        // store handed out by reads point straight into its mapping, and
+        // touching one after the file under it had shrunk would crash the
+        // process. So a store that has been mapped is never cut in place,
+        // even once unsealed, the part being kept is copied to a new file
+        // instead and the old one is left alone for as long as those slices
+        // are around.
+        self.sealed = None;
+
+        // With nothing to cut the segment still ends at the cut, which is
+        // past its last record when compaction removed the ones after it
+        let Some(position) = cut else {
+            self.next_offset = self.next_offset.max(offset);
+            return Ok(());
+        };
+        if self.mapped {
+            self.replace_store(position)?;
+        }
+        let relative_offset = (offset - self.base_offset) as u32;
+
+        let mut safe_store = self.store.lock().unwrap();
+        safe_store.truncate(position)?;
+        self.index.truncate(relative_offset)?;
+        self.timeindex.truncate(relative_offset)?;
+        self.next_offset = offset;
+        self.store_size = position;
+        drop(safe_store);
+
+        let (_, _, max_timestamp) = self.find_tail()?;
+        self.max_timestamp = max_timestamp;
+
+        Ok(())
+    }
+
+    // Copy the first `len` bytes of the store to a new file, move it over
+    // the old one and carry on with the new file
+    fn replace_store(&mut self, len: u64) -> Result<()> {
+        let copy_path = format!("{}.truncate", self.store_path);
+        let mut copy = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&copy_path)?;
+        io::copy(&mut File::open(&self.store_path)?.take(len), &mut copy)?;
+        copy.sync_all()?;
+        drop(copy);
+
+        fs::rename(&copy_path, &self.store_path)?;
+        if let Some(dir) = Path::new(&self.store_path).parent() {
+            File::open(dir)?.sync_all()?;
+        }
+
+        let file = open_store(&self.store_path)?;
+        self.store = store::new(&file.try_clone()?, self.store_path.clone(), &self.config)?;
+        self.file = file;
+        self.store_size = len;
+        self.mapped = false;
+
+        Ok(())
+    }
+
+    // Where the frame for `offset` starts in the store, or the frame for the
+    // next record after it if compaction has removed `offset`
+    pub fn position(&self, offset: u64) -> Result<u64> {
+        let (_, position, _) = self.seek(offset)?;
+        Ok(position)
+    }
+
+    // Path of the store file backing this segment
+    pub fn store_path(&self) -> String {
+        self.store_path.clone()
+    }
+
+    // Bytes of record data held in the store
+    pub fn size(&self) -> u64 {
+        self.store_size
+    }
+
+    // Newest record timestamp in the segment, in milliseconds since the
+    // epoch, or 0 if it holds no records
+    pub fn max_timestamp(&self) -> u64 {
+        self.max_timestamp
     }
 
     pub fn is_maxed(&mut self) -> bool {
         let safe_store = self.store.lock().unwrap();
-        safe_store.size >= self.config.segment.max_store_bytes
-            || self.index.size >= self.config.segment.max_index_bytes
+        safe_store.size >= self.config.segment.max_store_bytes || self.records() >= self.max_records()
+    }
+
+    // Force the store and then the indexes out to disk regardless of the
+    // durability mode, so the indexes never point past synced data
+    pub fn sync(&self) -> Result<()> {
+        let mut safe_store = self.store.lock().unwrap();
+        safe_store.sync()?;
+        self.index.sync()?;
+        self.timeindex.sync()?;
+
+        Ok(())
     }
 
     pub fn remove(&mut self) -> Result<()> {
         self.close()?;
         
-        // Remove index file
-        remove_file(&self.index.path)?;
+        // Remove index files
+        remove_file(self.index.path())?;
+        remove_file(self.timeindex.path())?;
         
         // Remove store file
         let safe_store = self.store.lock().unwrap();
@@ -136,8 +666,9 @@ impl Segment {
     }
 
     pub fn close(&mut self) -> Result<()> {
-        // Close index
+        // Close indexes
         self.index.close()?;
+        self.timeindex.close()?;
 
         // Close store
         let mut safe_store = self.store.lock().unwrap();
@@ -1,18 +1,62 @@
 use byteorder::{BigEndian, ByteOrder};
+use bytes::Bytes;
+use std::borrow::Cow;
+use std::fmt;
 use std::fs::File;
 
-use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::io::{self, BufWriter, Read, Write};
+use std::mem;
+use std::os::unix::fs::FileExt;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
+use super::error::LogError;
+use super::{config, encryption, format};
 
 // Custom Result type to match other modules
-pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+pub type Result<T> = std::result::Result<T, LogError>;
 
+// Every store file starts with a header of [magic: 4 bytes][version: u32]
+pub const MAGIC: &[u8; 4] = b"WSTR";
+pub const HEADER_WIDTH: u64 = 8;
+
+// Each frame after the header is laid out as
+// [len: u64][crc32c: u32][attributes: u8][payload]
+// where the checksum covers the attributes and the payload, and the length
+// is that of the payload as stored. The attribute byte describes how the
+// payload is encoded.
 const LEN_WIDTH: usize = 8;
+const CRC_WIDTH: usize = 4;
+const ATTR_WIDTH: usize = 1;
+pub const FRAME_HEADER_WIDTH: usize = LEN_WIDTH + CRC_WIDTH + ATTR_WIDTH;
+
+// The low bits of the attribute byte name the codec the payload was
+// compressed with, and the next one is set when the compressed payload was
+// then encrypted. The remaining bits are reserved and must be zero.
+const CODEC_MASK: u8 = 0x07;
+const ENCRYPTED: u8 = 0x08;
+const CODEC_NONE: u8 = 0;
+const CODEC_LZ4: u8 = 1;
+const CODEC_ZSTD: u8 = 2;
+const CODEC_SNAPPY: u8 = 3;
+const ZSTD_LEVEL: i32 = 3;
 const BUFFER_CAPACITY: usize = 64 * 1024;
-const FLUSH_THRESHOLD: usize = 64 * 1024; // 64 KB -> This supports frequent flushing
-const SYNC_THRESHOLD: usize = 256 * 1024; // 256 KB -> Reduces the volume of sys calls.
-const SYNC_INTERVAL: Duration = Duration::from_millis(1000);
+
+// Returned when a frame read back from the store does not match what was
+// written, either from bit rot or from a write that never fully hit disk
+#[derive(Debug, Clone)]
+pub struct CorruptRecord {
+    pub path: String,
+    pub pos: u64,
+    pub reason: String,
+}
+
+impl fmt::Display for CorruptRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Corrupt record in {} at position {}: {}", self.path, self.pos, self.reason)
+    }
+}
+
+impl std::error::Error for CorruptRecord {}
 
 // The base object we will work with
 // This will be wrapped in a mutex for safety during usage
@@ -22,110 +66,368 @@ pub struct Store {
     pub path: String,
     pub buf: BufWriter<File>,
     pub size: u64,
+    durability: config::Durability,
+    compression: config::Compression,
+    keys: Option<Arc<encryption::KeyRing>>,
     last_sync: Instant,
-    bytes_buffered: usize,
-    sync_bytes: usize,
+    sync_bytes: u64,
 }
 
 pub type SafeStore = Arc<Mutex<Store>>;
 
-pub fn new(file: &File, path: String) -> Result<SafeStore> {
-    let size = file.metadata()
-        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?
-        .len() as u64;
-    let file_obj = file.try_clone()
-        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
+pub fn new(file: &File, path: String, conf: &config::Config) -> Result<SafeStore> {
+    let size = file.metadata()?.len();
+    let file_obj = file.try_clone()?;
+
+    let mut writer = BufWriter::with_capacity(BUFFER_CAPACITY, file.try_clone().expect("clone failed"));
+
+    // A new store starts out with a header that has not been synced yet
+    let sync_bytes = if size == 0 { HEADER_WIDTH } else { 0 };
+
+    let size = if size == 0 {
+        // A brand new store, stamp it with the current format
+        let mut header = [0u8; HEADER_WIDTH as usize];
+        header[..MAGIC.len()].copy_from_slice(MAGIC);
+        BigEndian::write_u32(&mut header[MAGIC.len()..], format::VERSION);
+        writer.write_all(&header)?;
+        writer.flush()?;
+        HEADER_WIDTH
+    } else {
+        let version = format::store_version(&path)?;
+        if version != format::VERSION {
+            return Err(LogError::InvalidFormat(format!(
+                "{} has segment format version {}, expected {}", path, version, format::VERSION)));
+        }
+        size
+    };
 
-    let writer = BufWriter::new(file.try_clone().expect("clone failed"));
     Ok(Arc::new(Mutex::new(Store {
         file: file_obj,
-        path: path,
-        size: size,
+        path,
+        size,
         buf: writer,
+        durability: conf.durability.clone(),
+        compression: conf.compression,
+        keys: conf.encryption.clone(),
         last_sync: Instant::now(),
-        bytes_buffered: 0,
-        sync_bytes: 0
+        sync_bytes,
     })))
 }
+// Read and verify the frame starting at `pos` from a reader already
+// positioned there, returning its decrypted and decompressed payload and the
+// position just past the frame. `end` is where the valid data in the store
+// stops, so a garbled length is caught before it sends us reading off the
+// end. `keys` are only needed when the frame is encrypted.
+pub fn read_frame<R: Read>(
+    reader: &mut R,
+    path: &str,
+    pos: u64,
+    end: u64,
+    keys: Option<&encryption::KeyRing>,
+) -> Result<(Vec<u8>, u64)> {
+    if pos + FRAME_HEADER_WIDTH as u64 > end {
+        return Err(corrupt(path, pos, "frame header extends past end of store"));
+    }
+    let mut header = [0u8; FRAME_HEADER_WIDTH];
+    reader.read_exact(&mut header)?;
+
+    let len = frame_len(&header, path, pos, end)?;
+    let mut b = vec![0u8; len as usize];
+
+    // Read the actual bytes
+    reader.read_exact(&mut b)?;
+
+    let attrs = verify_frame(&header, &b, path, pos)?;
+    let next = pos + FRAME_HEADER_WIDTH as u64 + len;
+    match open_payload(attrs, &b, keys, path, pos)? {
+        Some(payload) => Ok((payload, next)),
+        None => Ok((b, next)),
+    }
+}
+
+// Verify the frame at `pos` in a store that is mapped into memory, returning
+// its payload and the position just past the frame. Payloads stored as they
+// are come back as a slice of the same buffer rather than a copy.
+pub fn frame_in(data: &Bytes, path: &str, pos: u64, keys: Option<&encryption::KeyRing>) -> Result<(Bytes, u64)> {
+    let end = data.len() as u64;
+    if pos + FRAME_HEADER_WIDTH as u64 > end {
+        return Err(corrupt(path, pos, "frame header extends past end of store"));
+    }
+    let start = pos as usize + FRAME_HEADER_WIDTH;
+    let header = &data[pos as usize..start];
+
+    let len = frame_len(header, path, pos, end)?;
+    let payload = data.slice(start..start + len as usize);
+
+    let attrs = verify_frame(header, &payload, path, pos)?;
+    let next = pos + FRAME_HEADER_WIDTH as u64 + len;
+    match open_payload(attrs, &payload, keys, path, pos)? {
+        Some(opened) => Ok((opened.into(), next)),
+        None => Ok((payload, next)),
+    }
+}
+
+// Decode the payload length from a frame header
+fn frame_len(header: &[u8], path: &str, pos: u64, end: u64) -> Result<u64> {
+    let len = BigEndian::read_u64(&header[..LEN_WIDTH]);
+
+    // A torn or garbled length would otherwise send us reading past the
+    // end of the file, or allocating whatever size the bytes decode to
+    if len > end - pos - FRAME_HEADER_WIDTH as u64 {
+        return Err(corrupt(path, pos, &format!("frame length {} extends past end of store", len)));
+    }
+    Ok(len)
+}
+
+// Check the payload against the checksum and attributes in its header,
+// returning the attributes
+fn verify_frame(header: &[u8], payload: &[u8], path: &str, pos: u64) -> Result<u8> {
+    let expected_crc = BigEndian::read_u32(&header[LEN_WIDTH..LEN_WIDTH + CRC_WIDTH]);
+    let attrs = &header[LEN_WIDTH + CRC_WIDTH..];
+
+    // Verify the data matches what was written
+    let actual_crc = crc32c::crc32c_append(crc32c::crc32c(attrs), payload);
+    if actual_crc != expected_crc {
+        return Err(corrupt(path, pos, &format!(
+            "checksum mismatch (expected {:#010x}, found {:#010x})", expected_crc, actual_crc)));
+    }
+    if attrs[0] & !(CODEC_MASK | ENCRYPTED) != 0 || attrs[0] & CODEC_MASK > CODEC_SNAPPY {
+        return Err(corrupt(path, pos, &format!("unknown frame attributes {:#04x}", attrs[0])));
+    }
+    Ok(attrs[0])
+}
+
+// Undo the encryption and compression of a verified payload, or None if it
+// was stored as it is. The checksum has already ruled out a torn write, so a
+// payload that fails to decrypt is a missing or wrong key rather than
+// something recovery should cut away, and is not reported as corrupt.
+fn open_payload(
+    attrs: u8,
+    payload: &[u8],
+    keys: Option<&encryption::KeyRing>,
+    path: &str,
+    pos: u64,
+) -> Result<Option<Vec<u8>>> {
+    let codec = attrs & CODEC_MASK;
+    if attrs & ENCRYPTED == 0 {
+        if codec == CODEC_NONE {
+            return Ok(None);
+        }
+        return decompress(codec, payload, path, pos).map(Some);
+    }
+
+    let keys = keys.ok_or_else(|| {
+        LogError::Encryption(format!("Record in {} at position {} is encrypted but no keys are configured", path, pos))
+    })?;
+    let plaintext = keys.decrypt(&[attrs], payload).map_err(|e| {
+        LogError::Encryption(format!("Failed to decrypt record in {} at position {}: {}", path, pos, e))
+    })?;
+
+    if codec == CODEC_NONE {
+        return Ok(Some(plaintext));
+    }
+    decompress(codec, &plaintext, path, pos).map(Some)
+}
+
+// Compress a payload with the configured codec, returning the codec that
+// was actually used. Payloads that would not shrink are stored as they are.
+fn compress(compression: config::Compression, p: &[u8]) -> Result<(u8, Cow<'_, [u8]>)> {
+    let (codec, compressed) = match compression {
+        config::Compression::None => return Ok((CODEC_NONE, Cow::Borrowed(p))),
+        config::Compression::Lz4 => (CODEC_LZ4, lz4_flex::compress_prepend_size(p)),
+        config::Compression::Zstd => (CODEC_ZSTD, zstd::bulk::compress(p, ZSTD_LEVEL)?),
+        config::Compression::Snappy => (CODEC_SNAPPY, snap::raw::Encoder::new().compress_vec(p).map_err(io::Error::from)?),
+    };
+
+    if compressed.len() >= p.len() {
+        return Ok((CODEC_NONE, Cow::Borrowed(p)));
+    }
+    Ok((codec, Cow::Owned(compressed)))
+}
+
+// Undo the compression of the frame at `pos`
+fn decompress(codec: u8, payload: &[u8], path: &str, pos: u64) -> Result<Vec<u8>> {
+    let decompressed = match codec {
+        CODEC_LZ4 => lz4_flex::decompress_size_prepended(payload).map_err(|e| e.to_string()),
+        CODEC_ZSTD => zstd::stream::decode_all(payload).map_err(|e| e.to_string()),
+        _ => snap::raw::Decoder::new().decompress_vec(payload).map_err(|e| e.to_string()),
+    };
+
+    // The checksum matched, so this is not a torn write but it is still
+    // not something that can be read back
+    decompressed.map_err(|e| corrupt(path, pos, &format!("failed to decompress payload: {}", e)))
+}
+
+// Read and verify the frame at `pos` with positional reads, leaving the
+// file's cursor alone so any number of readers can share one handle
+pub fn read_frame_at(
+    file: &File,
+    path: &str,
+    pos: u64,
+    end: u64,
+    keys: Option<&encryption::KeyRing>,
+) -> Result<(Vec<u8>, u64)> {
+    read_frame(&mut PositionedReader { file, pos }, path, pos, end, keys)
+}
+
+// Adapts pread to `Read`, advancing its own position instead of the file's
+struct PositionedReader<'a> {
+    file: &'a File,
+    pos: u64,
+}
+
+impl Read for PositionedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.file.read_at(buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+pub(crate) fn corrupt(path: &str, pos: u64, reason: &str) -> LogError {
+    LogError::Corrupt(CorruptRecord {
+        path: path.to_string(),
+        pos,
+        reason: reason.to_string(),
+    })
+}
+
 impl Store {
     // Append a slice of bytes to the store log
     pub fn append(&mut self, p: &[u8]) -> Result<(u64, u64)> {
-        
+        let (written, pos) = self.write(p)?;
+        self.commit()?;
+
+        // Return the number of written bytes and the position
+        Ok((written, pos))
+    }
+
+    // Buffer a frame for the given bytes without flushing, so several frames
+    // can be written before paying for a single `commit`. The bytes are
+    // compressed and then encrypted first if the store is configured to.
+    pub fn write(&mut self, p: &[u8]) -> Result<(u64, u64)> {
         let pos = self.size;
+        let (mut attrs, compressed) = compress(self.compression, p)?;
+        let p = match self.keys {
+            Some(ref keys) => {
+                attrs |= ENCRYPTED;
+                Cow::Owned(keys.encrypt(&[attrs], &compressed)?)
+            }
+            None => compressed,
+        };
+        let p = &*p;
 
         // Write the length of the data
         let mut len_buf = [0u8; LEN_WIDTH];
         BigEndian::write_u64(&mut len_buf, p.len() as u64);
-        self.buf.write_all(&len_buf)
-            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
+        self.buf.write_all(&len_buf)?;
+
+        // Write the checksum of the attributes and data, then the attributes
+        let attrs = [attrs; ATTR_WIDTH];
+        let mut crc_buf = [0u8; CRC_WIDTH];
+        BigEndian::write_u32(&mut crc_buf, crc32c::crc32c_append(crc32c::crc32c(&attrs), p));
+        self.buf.write_all(&crc_buf)?;
+        self.buf.write_all(&attrs)?;
 
         // Write the actual data
-        self.buf.write_all(p)
-            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
+        self.buf.write_all(p)?;
 
         // Track the number of bytes written manually
-        let written = p.len() + LEN_WIDTH;
+        let written = p.len() + FRAME_HEADER_WIDTH;
         self.size += written as u64;
+        self.sync_bytes += written as u64;
 
-        // Flush any contents in the buffer
-        // This pushes to from in-memory to OS Page Cache
-        
-        if self.buf.buffer().len() >= BUFFER_CAPACITY {
-            
-            self.buf.flush()
-                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
-
-            // Sync data that exists in the buffer
-            // Pushes from OS Page Cache to Disk
-            self.file.sync_all()
-                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
+        Ok((written as u64, pos))
+    }
+
+    // Hand everything buffered so far to the OS, then fsync if the
+    // durability mode says it is time. Returns whether an fsync happened, so
+    // the caller can sync anything that has to stay in step with the store.
+    pub fn commit(&mut self) -> Result<bool> {
+        // Pushes from in-memory to OS Page Cache
+        self.buf.flush()?;
+
+        let sync_due = match self.durability {
+            config::Durability::EveryWrite => self.sync_bytes > 0,
+            config::Durability::Periodic { bytes, interval_ms } => {
+                self.sync_bytes >= bytes
+                    || (self.sync_bytes > 0
+                        && self.last_sync.elapsed() >= Duration::from_millis(interval_ms))
+            }
+            config::Durability::OsManaged => false,
+        };
+
+        if sync_due {
+            self.sync()?;
         }
+        Ok(sync_due)
+    }
 
-        // Return the number of written bytes and the position
-        Ok((written as u64, pos))
+    // Force everything written so far out to disk, whatever the durability
+    // mode. A no-op if nothing has been written since the last sync.
+    pub fn sync(&mut self) -> Result<()> {
+        if self.sync_bytes == 0 {
+            return Ok(());
+        }
+
+        // Pushes from in-memory to OS Page Cache
+        self.buf.flush()?;
+
+        // Pushes from OS Page Cache to Disk
+        self.file.sync_all()?;
+
+        self.last_sync = Instant::now();
+        self.sync_bytes = 0;
+        Ok(())
     }
 
     pub fn read(&mut self, pos: u64) -> Result<Vec<u8>> {
 
         // Flush any contents in the buffer
-        self.buf.flush()
-            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
-        let mut size = vec![0u8; LEN_WIDTH];
+        self.buf.flush()?;
 
-        // Start reading from the given position
-        self.file.seek(SeekFrom::Start(pos))
-            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
-        self.file.read_exact(&mut size)
-            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
-
-        // Encode size
-        let new_pos = u64::from_be_bytes(size.try_into().unwrap());
-        let mut b = vec![0u8; new_pos as usize];
-
-        // Read the actual bytes
-        self.file.seek(SeekFrom::Start(pos + LEN_WIDTH as u64))
-            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
-        self.file.read_exact(&mut b)
-            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
-        Ok(b)
+        let (payload, _) = read_frame_at(&self.file, &self.path, pos, self.size, self.keys.as_deref())?;
+        Ok(payload)
     }
 
     // Reads len(p) bytes into p, beginning at the offset in the
     // store file.
     pub fn read_at(&mut self, p: &mut [u8], off: u64) -> Result<usize> {
-        self.buf.flush()
-            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
-        self.file.seek(SeekFrom::Start(off))
-            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
-        self.file.read_exact(p)
-            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
+        self.buf.flush()?;
+        self.file.read_exact_at(p, off)?;
 
         Ok(p.len())
     }
 
+    // Cut the store back to the given size, dropping everything after it
+    pub fn truncate(&mut self, size: u64) -> Result<()> {
+        self.buf.flush()?;
+        self.file.set_len(size)?;
+        self.file.sync_all()?;
+        self.size = size;
+        self.last_sync = Instant::now();
+        self.sync_bytes = 0;
+
+        Ok(())
+    }
+
+    // Undo writes that failed to commit, cutting the store back to `size`.
+    // Whatever is still buffered belongs to them, so it is thrown away
+    // rather than flushed, and anything that did reach the file is cut off.
+    pub fn rollback(&mut self, size: u64) -> Result<()> {
+        let writer = BufWriter::with_capacity(BUFFER_CAPACITY, self.file.try_clone()?);
+        let _ = mem::replace(&mut self.buf, writer).into_parts();
+
+        self.file.set_len(size)?;
+        self.sync_bytes = self.sync_bytes.saturating_sub(self.size.saturating_sub(size));
+        self.size = size;
+
+        Ok(())
+    }
+
     pub fn close(&mut self) -> Result<()> {
-        self.buf.flush()
-            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
+        self.buf.flush()?;
+        self.sync()?;
         
         Ok(())
     }
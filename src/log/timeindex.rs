@@ -0,0 +1,131 @@
+use crate::log::error::LogError;
+use crate::log::{config, mapped};
+use std::fs::File;
+
+// Custom Result type to match other modules
+pub type Result<T> = std::result::Result<T, LogError>;
+
+const TS_WIDTH: u64 = 8;
+const OFF_WIDTH: u64 = 4;
+const ENT_WIDTH: u64 = TS_WIDTH + OFF_WIDTH;
+
+// Laid out like the offset index, see `mapped`, with entries of
+// [timestamp: u64][relative offset: u32].
+// Entries are only considered for records that also get an offset index
+// entry, and hold the highest record timestamp seen up to and including
+// that record. One is only added when that timestamp has moved on since the
+// last entry, so timestamps strictly increase along the file.
+const MAGIC: &[u8; 4] = b"WTIX";
+
+pub struct TimeIndex {
+    file: mapped::MappedFile,
+}
+
+pub fn new(file: &File, path: String, conf: &config::Config) -> Result<TimeIndex> {
+    Ok(TimeIndex {
+        file: mapped::open(file, path, MAGIC, "time index", ENT_WIDTH, conf)?,
+    })
+}
+
+impl TimeIndex {
+    pub fn close(&mut self) -> Result<()> {
+        self.file.close()
+    }
+
+    // Shrink the file to the entries it holds
+    pub fn trim(&mut self) -> Result<()> {
+        self.file.trim()
+    }
+
+    // Write the mapped entries back to the file
+    pub fn sync(&self) -> Result<()> {
+        self.file.sync()
+    }
+
+    pub fn path(&self) -> &str {
+        &self.file.path
+    }
+
+    // Number of entries currently held in the index
+    pub fn entries(&self) -> u64 {
+        self.file.entries()
+    }
+
+    // The entry at position `i`, as (timestamp, relative offset)
+    fn entry(&self, i: u64) -> (u64, u32) {
+        let entry = self.file.entry(i);
+        let mut ts_bytes = [0u8; TS_WIDTH as usize];
+        ts_bytes.copy_from_slice(&entry[..TS_WIDTH as usize]);
+        let mut off_bytes = [0u8; OFF_WIDTH as usize];
+        off_bytes.copy_from_slice(&entry[TS_WIDTH as usize..]);
+
+        (u64::from_be_bytes(ts_bytes), u32::from_be_bytes(off_bytes))
+    }
+
+    // The most recent entry, if there is one
+    pub fn last(&self) -> Option<(u64, u32)> {
+        match self.entries() {
+            0 => None,
+            n => Some(self.entry(n - 1)),
+        }
+    }
+
+    // Timestamp of the last entry for a relative offset at or before `off`
+    pub fn last_at(&self, off: u32) -> Option<u64> {
+        (0..self.entries())
+            .rev()
+            .map(|i| self.entry(i))
+            .find(|&(_, entry_off)| entry_off <= off)
+            .map(|(timestamp, _)| timestamp)
+    }
+
+    // Relative offset of the last entry whose timestamp is older than
+    // `timestamp`. No record up to that one reaches `timestamp`, so scanning
+    // forward from it finds the first record that does. None when no entry is
+    // older, in which case the scan has to start from the first record.
+    pub fn lookup(&self, timestamp: u64) -> Option<u32> {
+        // Timestamps strictly increase, so binary search for the first entry
+        // that is not older than the target
+        let (mut low, mut high) = (0, self.entries());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.entry(mid).0 < timestamp {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        if low == 0 {
+            return None;
+        }
+        Some(self.entry(low - 1).1)
+    }
+
+    // Drop every entry for a relative offset of `off` or later
+    pub fn truncate(&mut self, off: u32) -> Result<()> {
+        let mut entries = self.entries();
+        while entries > 0 && self.entry(entries - 1).1 >= off {
+            entries -= 1;
+        }
+
+        self.file.truncate(entries)
+    }
+
+    // Note the highest timestamp seen as of the record at relative offset
+    // `off`, adding an entry only if it has moved on since the last one
+    pub fn append(&mut self, timestamp: u64, off: u32) -> Result<()> {
+        if self.last().is_some_and(|(last, _)| timestamp <= last) {
+            return Ok(());
+        }
+        self.write(timestamp, off)
+    }
+
+    fn write(&mut self, timestamp: u64, off: u32) -> Result<()> {
+        let mut entry = [0u8; ENT_WIDTH as usize];
+        entry[..TS_WIDTH as usize].copy_from_slice(&timestamp.to_be_bytes());
+        entry[TS_WIDTH as usize..].copy_from_slice(&off.to_be_bytes());
+
+        self.file.push(&entry)
+    }
+}
@@ -1,16 +1,38 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use std::net::SocketAddr;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 use tracing::{error, info, Level};
 use tracing_subscriber;
 
 use walrus::cluster::config::ClusterConfig;
 use walrus::cluster::state::ClusterStateManager;
-use walrus::log::config;
+use walrus::log::{config, encryption};
 use walrus::log::log::Log;
 use walrus::server::WalServer;
 
+const RETENTION_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(ValueEnum, Clone, Debug)]
+enum DurabilityMode {
+    /// fsync after every write
+    EveryWrite,
+    /// fsync after --sync-bytes or --sync-interval-ms, whichever comes first
+    Periodic,
+    /// Leave writing back to disk to the OS
+    Os,
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+enum CompressionCodec {
+    /// Store records as they are
+    None,
+    Lz4,
+    Zstd,
+    Snappy,
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
@@ -30,10 +52,62 @@ struct Args {
     #[arg(long, default_value = "1048576")]
     max_segment_bytes: u64,
 
-    /// Maximum index size in bytes
+    /// Index file size to preallocate in bytes, grown as needed
     #[arg(long, default_value = "1048576")]
     max_index_bytes: u64,
 
+    /// Maximum bytes of record data to retain before deleting old segments (0 = unlimited)
+    #[arg(long, default_value = "0")]
+    retention_bytes: u64,
+
+    /// Age in milliseconds of the newest record in a closed segment before it is deleted (0 = unlimited)
+    #[arg(long, default_value = "0")]
+    retention_ms: u64,
+
+    /// Milliseconds between compactions of closed segments by key (0 = never compact)
+    #[arg(long, default_value = "0")]
+    compaction_interval_ms: u64,
+
+    /// Milliseconds a tombstone is kept once it is the latest record for its key
+    #[arg(long, default_value = "86400000")]
+    tombstone_retention_ms: u64,
+
+    /// Free disk space in bytes to keep in reserve, writes are refused below it (0 = no reserve)
+    #[arg(long, default_value = "67108864")]
+    disk_reserve_bytes: u64,
+
+    /// When writes are fsynced to disk
+    #[arg(long, value_enum, default_value = "periodic")]
+    durability: DurabilityMode,
+
+    /// Bytes written between fsyncs in periodic durability mode
+    #[arg(long, default_value = "262144")]
+    sync_bytes: u64,
+
+    /// Milliseconds between fsyncs in periodic durability mode, at least 1
+    #[arg(long, default_value = "1000", value_parser = clap::value_parser!(u64).range(1..))]
+    sync_interval_ms: u64,
+
+    /// Codec new records are compressed with in the store
+    #[arg(long, value_enum, default_value = "none")]
+    compression: CompressionCodec,
+
+    /// Key file to encrypt records in the store with, one "<key-id> <hex key>" per line
+    #[arg(long)]
+    encryption_key_file: Option<String>,
+
+    /// Records in a segment before rolling to a new one (0 = as many as relative offsets allow)
+    #[arg(long, default_value = "0")]
+    max_records_per_segment: u64,
+
+    /// Store bytes between index entries (0 = index every record)
+    #[arg(long, default_value = "0")]
+    index_interval_bytes: u64,
+
+    /// Microseconds concurrent writes are collected into one group commit
+    #[arg(long, default_value = "500")]
+    group_commit_window_us: u64,
+
     /// Election timeout in milliseconds
     #[arg(long, default_value = "1000")]
     election_timeout_ms: u64,
@@ -66,21 +140,103 @@ async fn main() -> anyhow::Result<()> {
     cluster_config.data_dir = args.data_dir;
     cluster_config.max_segment_bytes = args.max_segment_bytes;
     cluster_config.max_index_bytes = args.max_index_bytes;
+    cluster_config.group_commit_window_us = args.group_commit_window_us;
     cluster_config.election_timeout_ms = args.election_timeout_ms;
     cluster_config.heartbeat_interval_ms = args.heartbeat_interval_ms;
 
+    let encryption = match args.encryption_key_file {
+        Some(ref path) => Some(Arc::new(
+            encryption::load(path).map_err(|e| anyhow::anyhow!("Failed to load encryption keys: {}", e))?,
+        )),
+        None => None,
+    };
+
     // Create WAL log configuration
     let log_config = config::Config {
         segment: config::InitSegment {
             max_store_bytes: cluster_config.max_segment_bytes,
             max_index_bytes: cluster_config.max_index_bytes,
             initial_offset: 0,
+            index_interval_bytes: args.index_interval_bytes,
+            max_records_per_segment: args.max_records_per_segment,
         },
+        retention: config::Retention {
+            max_bytes: args.retention_bytes,
+            max_age_ms: args.retention_ms,
+            min_offset: 0,
+        },
+        compaction: config::Compaction {
+            tombstone_retention_ms: args.tombstone_retention_ms,
+        },
+        disk: config::Disk {
+            reserve_bytes: args.disk_reserve_bytes,
+        },
+        durability: match args.durability {
+            DurabilityMode::EveryWrite => config::Durability::EveryWrite,
+            DurabilityMode::Periodic => config::Durability::Periodic {
+                bytes: args.sync_bytes,
+                interval_ms: args.sync_interval_ms,
+            },
+            DurabilityMode::Os => config::Durability::OsManaged,
+        },
+        compression: match args.compression {
+            CompressionCodec::None => config::Compression::None,
+            CompressionCodec::Lz4 => config::Compression::Lz4,
+            CompressionCodec::Zstd => config::Compression::Zstd,
+            CompressionCodec::Snappy => config::Compression::Snappy,
+        },
+        encryption,
     };
 
     // Create WAL log
+    let durability = log_config.durability.clone();
     let log = Log::new(cluster_config.data_dir.clone(), log_config).map_err(|e| anyhow::anyhow!("Failed to create log: {}", e))?;
 
+    // Time-based retention has to run even while nothing is being written
+    let retention_log = log.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RETENTION_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = retention_log.write().unwrap().enforce_retention() {
+                error!("Failed to enforce retention: {}", e);
+            }
+        }
+    });
+
+    if args.compaction_interval_ms > 0 {
+        let compaction_log = log.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(args.compaction_interval_ms));
+            loop {
+                interval.tick().await;
+                // Compaction reads and rewrites whole segments, so keep it
+                // off the runtime's worker threads
+                let log = compaction_log.clone();
+                match tokio::task::spawn_blocking(move || Log::compact(&log)).await {
+                    Ok(Err(e)) => error!("Failed to compact log: {}", e),
+                    Err(e) => error!("Compaction task failed: {}", e),
+                    Ok(Ok(_)) => {}
+                }
+            }
+        });
+    }
+
+    // Appends only check the sync interval as they arrive, so make sure the
+    // tail of a burst of writes still gets synced once things go quiet
+    if let config::Durability::Periodic { interval_ms, .. } = durability {
+        let sync_log = log.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(interval_ms));
+            loop {
+                interval.tick().await;
+                if let Err(e) = sync_log.read().unwrap().sync() {
+                    error!("Failed to sync log: {}", e);
+                }
+            }
+        });
+    }
+
     // Create cluster state manager
     let state_manager = Arc::new(ClusterStateManager::new(args.node_id.clone()));
 
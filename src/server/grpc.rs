@@ -1,7 +1,10 @@
 use tonic::{transport::Server, Request, Response, Status};
 use crate::cluster::state::ClusterStateManager;
 use crate::cluster::config::ClusterConfig;
+use crate::log::commit::GroupCommit;
+use crate::log::error::LogError;
 use crate::log::log::SafeLog;
+use prost::Message;
 use std::sync::Arc;
 use tracing::{error, info};
 
@@ -11,18 +14,29 @@ pub mod proto {
 }
 
 use proto::log_server::{Log, LogServer};
-use proto::{WriteRequest, WriteResponse, ReadRequest, ReadResponse, Record};
+use proto::{
+    WriteRequest, WriteResponse, WriteBatchRequest, WriteBatchResponse, ReadRequest, ReadResponse,
+    OffsetsRequest, OffsetsResponse, OffsetForTimestampRequest, OffsetForTimestampResponse, Record,
+};
 
 pub struct WalServer {
     log: SafeLog,
+    committer: GroupCommit,
     state_manager: Arc<ClusterStateManager>,
     config: ClusterConfig,
 }
 
 impl WalServer {
     pub fn new(log: SafeLog, state_manager: Arc<ClusterStateManager>, config: ClusterConfig) -> Self {
+        let committer = GroupCommit::new(
+            log.clone(),
+            config.group_commit_window(),
+            config.group_commit_max_records,
+        );
+
         Self {
             log,
+            committer,
             state_manager,
             config,
         }
@@ -59,20 +73,54 @@ impl Log for WalServer {
         // Extract the record
         let record = req.record.ok_or_else(|| Status::invalid_argument("No record provided"))?;
         
-        // Append to log
-        let mut log_guard = self.log.lock().unwrap();
-        let mut wal_record = crate::log::segment::Record::default();
-        wal_record.value = record.value;
-        wal_record.offset = record.offset;
-        
-        match log_guard.append(&mut wal_record) {
+        // Append to log alongside any other writes arriving at the same time
+        match self.committer.append(to_wal_record(record)).await {
             Ok(offset) => {
                 info!("Successfully wrote record at offset {}", offset);
                 Ok(Response::new(WriteResponse { offset }))
             }
             Err(e) => {
                 error!("Failed to write record: {}", e);
-                Err(Status::internal(format!("Failed to write record: {}", e)))
+                Err(to_status(e, "Failed to write record"))
+            }
+        }
+    }
+
+    async fn write_batch(
+        &self,
+        request: Request<WriteBatchRequest>,
+    ) -> Result<Response<WriteBatchResponse>, Status> {
+        let req = request.into_inner();
+
+        // Check if we're the leader
+        if !self.state_manager.is_leader() {
+            return Err(Status::failed_precondition("Not the leader"));
+        }
+
+        if req.records.is_empty() {
+            return Err(Status::invalid_argument("No records provided"));
+        }
+
+        let mut wal_records: Vec<crate::log::segment::Record> = req
+            .records
+            .into_iter()
+            .map(to_wal_record)
+            .collect();
+
+        // Append the whole batch under one lock
+        let mut log_guard = self.log.write().unwrap();
+
+        match log_guard.append_batch(&mut wal_records) {
+            Ok(offsets) => {
+                info!("Successfully wrote {} records at offsets {:?}", wal_records.len(), offsets);
+                Ok(Response::new(WriteBatchResponse {
+                    start_offset: offsets.start,
+                    end_offset: offsets.end,
+                }))
+            }
+            Err(e) => {
+                error!("Failed to write batch: {}", e);
+                Err(to_status(e, "Failed to write batch"))
             }
         }
     }
@@ -84,24 +132,95 @@ impl Log for WalServer {
         let req = request.into_inner();
         let offset = req.offset;
         
-        // Read from log
-        let mut log_guard = self.log.lock().unwrap();
+        // Read from log. The record is decoded straight from its stored
+        // bytes, so for sealed segments its key and value are slices of the
+        // mapped store rather than copies.
+        let log_guard = self.log.read().unwrap();
         
-        match log_guard.read(offset) {
+        match log_guard.read_bytes(offset).and_then(|bytes| Ok(Record::decode(bytes)?)) {
             Ok(record) => {
-                let proto_record = Record {
-                    value: record.value,
-                    offset: record.offset,
-                };
-                
                 Ok(Response::new(ReadResponse {
-                    record: Some(proto_record),
+                    record: Some(record),
                 }))
             }
             Err(e) => {
                 error!("Failed to read record at offset {}: {}", offset, e);
-                Err(Status::not_found(format!("Record not found at offset {}: {}", offset, e)))
+                Err(to_status(e, &format!("Failed to read record at offset {}", offset)))
+            }
+        }
+    }
+
+    async fn offsets(
+        &self,
+        _request: Request<OffsetsRequest>,
+    ) -> Result<Response<OffsetsResponse>, Status> {
+        let log_guard = self.log.read().unwrap();
+
+        let response = match (log_guard.lowest_offset(), log_guard.highest_offset()) {
+            (Some(lowest_offset), Some(highest_offset)) => OffsetsResponse {
+                lowest_offset,
+                highest_offset,
+                empty: false,
+            },
+            _ => OffsetsResponse {
+                lowest_offset: 0,
+                highest_offset: 0,
+                empty: true,
+            },
+        };
+
+        Ok(Response::new(response))
+    }
+
+    async fn offset_for_timestamp(
+        &self,
+        request: Request<OffsetForTimestampRequest>,
+    ) -> Result<Response<OffsetForTimestampResponse>, Status> {
+        let timestamp = request.into_inner().timestamp;
+        let log_guard = self.log.read().unwrap();
+
+        let response = match log_guard.offset_for_timestamp(timestamp) {
+            Ok(Some(offset)) => OffsetForTimestampResponse { offset, found: true },
+            Ok(None) => OffsetForTimestampResponse { offset: 0, found: false },
+            Err(e) => {
+                error!("Failed to look up timestamp {}: {}", timestamp, e);
+                return Err(to_status(e, &format!("Failed to look up timestamp {}", timestamp)));
             }
+        };
+
+        Ok(Response::new(response))
+    }
+}
+
+// Pick the status code that tells the client what went wrong, so it can
+// tell an offset it should not have asked for from a server that is in
+// trouble
+fn to_status(e: LogError, context: &str) -> Status {
+    let message = format!("{}: {}", context, e);
+    match e {
+        LogError::OffsetOutOfRange { .. } => Status::out_of_range(message),
+        LogError::OffsetRemoved(_) => Status::not_found(message),
+        LogError::Corrupt(_) | LogError::Decode(_) => Status::data_loss(message),
+        LogError::ReadOnly { .. } | LogError::DiskFull(_) | LogError::SegmentFull { .. } => {
+            Status::resource_exhausted(message)
         }
+        LogError::Encryption(_) => Status::failed_precondition(message),
+        LogError::Unavailable(_) => Status::unavailable(message),
+        LogError::OffsetOutOfOrder { .. }
+        | LogError::InvalidFormat(_)
+        | LogError::InvalidConfig(_)
+        | LogError::DirectoryLocked { .. }
+        | LogError::Io(_) => Status::internal(message),
+    }
+}
+
+// The server and the log each generate their own copy of the Record message
+fn to_wal_record(record: Record) -> crate::log::segment::Record {
+    crate::log::segment::Record {
+        value: record.value.into(),
+        offset: record.offset,
+        key: record.key.into(),
+        headers: record.headers,
+        timestamp: record.timestamp,
     }
 }
@@ -5,8 +5,10 @@ use tokio::time::{sleep, Duration};
 use walrus::cluster::config::ClusterConfig;
 use walrus::cluster::state::ClusterStateManager;
 use walrus::cluster::election::LeaderElection;
+use walrus::client::{WalClient, WalClientService};
 use walrus::log::config;
 use walrus::log::log::Log;
+use walrus::server::WalServer;
 
 #[tokio::test]
 async fn test_leader_election() {
@@ -82,13 +84,16 @@ async fn test_wal_with_cluster() {
             max_store_bytes: 1024,
             max_index_bytes: 1024,
             initial_offset: 0,
+            index_interval_bytes: 0,
+            max_records_per_segment: 0,
         },
+        ..Default::default()
     };
     
     let log = Log::new("/tmp/test_wal_cluster".to_string(), log_config).unwrap();
     
     // Test basic WAL operations
-    let mut log_guard = log.lock().unwrap();
+    let mut log_guard = log.write().unwrap();
     
     let mut record = walrus::log::segment::Record::default();
     record.value = b"Test cluster WAL".to_vec();
@@ -123,3 +128,92 @@ async fn test_cluster_config() {
     assert_eq!(config.election_timeout().as_millis(), 1000);
     assert_eq!(config.heartbeat_interval().as_millis(), 100);
 }
+
+#[tokio::test]
+async fn test_client_reads_past_end_of_log() {
+    let data_dir = "/tmp/test_client_past_end";
+    std::fs::remove_dir_all(data_dir).ok();
+    let log = Log::new(data_dir.to_string(), config::Config::default()).unwrap();
+
+    let bind_addr: SocketAddr = "127.0.0.1:18093".parse().unwrap();
+    let cluster_config = ClusterConfig::new("node-1".to_string(), bind_addr);
+    let state_manager = Arc::new(ClusterStateManager::new("node-1".to_string()));
+    let server = WalServer::new(log, state_manager, cluster_config.clone());
+    let server_handle = tokio::spawn(server.start_server());
+
+    let mut client = None;
+    for _ in 0..50 {
+        if let Ok(connected) = WalClient::new(bind_addr).await {
+            client = Some(connected);
+            break;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    let mut client = client.expect("Server should come up");
+
+    // An empty log answers OUT_OF_RANGE, which the client reads as no record
+    assert_eq!(client.read(0).await.unwrap(), None);
+    assert_eq!(client.read(100).await.unwrap(), None);
+
+    // So the node is still found when probed for a leader
+    let mut service = WalClientService::new(cluster_config);
+    service.connect_to_node("node-1", bind_addr).await.unwrap();
+    assert_eq!(service.read(0).await.unwrap(), None);
+
+    server_handle.abort();
+    std::fs::remove_dir_all(data_dir).ok();
+}
+
+#[tokio::test]
+async fn test_client_reads_sealed_records() {
+    let data_dir = "/tmp/test_client_sealed_records";
+    std::fs::remove_dir_all(data_dir).ok();
+    let log_config = config::Config {
+        segment: config::InitSegment {
+            max_store_bytes: 256,
+            max_index_bytes: 1024,
+            initial_offset: 0,
+            index_interval_bytes: 0,
+            max_records_per_segment: 0,
+        },
+        ..Default::default()
+    };
+    let log = Log::new(data_dir.to_string(), log_config).unwrap();
+    {
+        let mut log_guard = log.write().unwrap();
+        for i in 0..20 {
+            let mut record = walrus::log::segment::Record::default();
+            record.key = format!("key-{}", i).into_bytes();
+            record.value = format!("Served message {}", i).into_bytes();
+            log_guard.append(&mut record).unwrap();
+        }
+        assert!(log_guard.segments.len() > 2, "Most records should be in sealed segments");
+    }
+
+    let bind_addr: SocketAddr = "127.0.0.1:18094".parse().unwrap();
+    let cluster_config = ClusterConfig::new("node-1".to_string(), bind_addr);
+    let state_manager = Arc::new(ClusterStateManager::new("node-1".to_string()));
+    let server = WalServer::new(log, state_manager, cluster_config);
+    let server_handle = tokio::spawn(server.start_server());
+
+    let mut client = None;
+    for _ in 0..50 {
+        if let Ok(connected) = WalClient::new(bind_addr).await {
+            client = Some(connected);
+            break;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    let mut client = client.expect("Server should come up");
+
+    // Records from sealed segments and the active one come back whole
+    for i in 0..20 {
+        let record = client.read_record(i).await.unwrap().unwrap();
+        assert_eq!(record.offset, i);
+        assert_eq!(record.key, format!("key-{}", i).into_bytes());
+        assert_eq!(record.value, format!("Served message {}", i).into_bytes());
+    }
+
+    server_handle.abort();
+    std::fs::remove_dir_all(data_dir).ok();
+}
@@ -0,0 +1,84 @@
+use std::fs;
+use walrus::log::config;
+use walrus::log::log::Log;
+use walrus::log::segment::Record;
+
+// The file size limit applies to the whole process, so this test lives in a
+// file of its own to keep it away from everything else writing files
+
+const TEST_DIR: &str = "/tmp/walrus_tests/failed_append";
+
+fn record(value: &str) -> Record {
+    let mut record = Record::default();
+    record.value = value.as_bytes().to_vec();
+    record
+}
+
+// Make writes past `limit` bytes into any file fail with EFBIG
+fn limit_file_size(limit: u64) -> libc::rlimit {
+    let mut original = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    unsafe {
+        // Fail the write instead of killing the process
+        libc::signal(libc::SIGXFSZ, libc::SIG_IGN);
+        assert_eq!(libc::getrlimit(libc::RLIMIT_FSIZE, &mut original), 0);
+        let limited = libc::rlimit { rlim_cur: limit, rlim_max: original.rlim_max };
+        assert_eq!(libc::setrlimit(libc::RLIMIT_FSIZE, &limited), 0);
+    }
+    original
+}
+
+#[test]
+fn test_failed_append_is_rolled_back() {
+    let _ = fs::remove_dir_all(TEST_DIR);
+    fs::create_dir_all(TEST_DIR).unwrap();
+    let config = config::Config {
+        segment: config::InitSegment {
+            max_store_bytes: 1024 * 1024,
+            max_index_bytes: 1024,
+            initial_offset: 0,
+            index_interval_bytes: 0,
+            max_records_per_segment: 0,
+        },
+        durability: config::Durability::EveryWrite,
+        ..Default::default()
+    };
+    let store_path = format!("{}/0.store", TEST_DIR);
+
+    {
+        let log = Log::new(TEST_DIR.to_string(), config.clone()).unwrap();
+        let mut log_guard = log.write().unwrap();
+        for i in 0..3 {
+            log_guard.append(&mut record(&format!("message {}", i))).unwrap();
+        }
+        let store_len = fs::metadata(&store_path).unwrap().len();
+
+        // Only part of the next frame fits before the write fails
+        let original = limit_file_size(store_len + 4);
+        let result = log_guard.append(&mut record("message 3"));
+        unsafe {
+            assert_eq!(libc::setrlimit(libc::RLIMIT_FSIZE, &original), 0);
+        }
+        assert!(result.is_err(), "The append should have hit the file size limit");
+
+        // Nothing of the failed append is left behind
+        assert_eq!(log_guard.next_offset(), 3);
+        assert_eq!(fs::metadata(&store_path).unwrap().len(), store_len);
+        assert!(log_guard.read(3).is_err());
+
+        // So the retry takes the same offset
+        assert_eq!(log_guard.append(&mut record("message 3")).unwrap(), 3);
+        assert_eq!(log_guard.read(3).unwrap().value, b"message 3");
+        log_guard.close().unwrap();
+    }
+
+    // And there is only the one copy of it after a reopen
+    let log = Log::new(TEST_DIR.to_string(), config).unwrap();
+    let log_guard = log.read().unwrap();
+    assert_eq!(log_guard.next_offset(), 4);
+    let values: Vec<Vec<u8>> = log_guard.reader(0).unwrap().map(|r| r.unwrap().value).collect();
+    let expected: Vec<Vec<u8>> = (0..4).map(|i| format!("message {}", i).into_bytes()).collect();
+    assert_eq!(values, expected);
+
+    drop(log_guard);
+    let _ = fs::remove_dir_all(TEST_DIR);
+}
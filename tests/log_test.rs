@@ -15,7 +15,10 @@ fn test_log_creation() {
             max_store_bytes: 1024,
             max_index_bytes: 1024,
             initial_offset: 0,
+            index_interval_bytes: 0,
+            max_records_per_segment: 0,
         },
+        ..Default::default()
     };
     
     let log_result = Log::new(test_dir.to_string(), config);
@@ -37,14 +40,17 @@ fn test_log_append_and_read() {
             max_store_bytes: 1024,
             max_index_bytes: 1024,
             initial_offset: 0,
+            index_interval_bytes: 0,
+            max_records_per_segment: 0,
         },
+        ..Default::default()
     };
     
     let log_result = Log::new(test_dir.to_string(), config);
     assert!(log_result.is_ok(), "Log creation should succeed");
     
     let log = log_result.unwrap();
-    let mut log_guard = log.lock().unwrap();
+    let mut log_guard = log.write().unwrap();
     
     // Create a test record
     let mut record = Record::default();
@@ -81,14 +87,17 @@ fn test_log_multiple_records() {
             max_store_bytes: 1024,
             max_index_bytes: 1024,
             initial_offset: 0,
+            index_interval_bytes: 0,
+            max_records_per_segment: 0,
         },
+        ..Default::default()
     };
     
     let log_result = Log::new(test_dir.to_string(), config);
     assert!(log_result.is_ok(), "Log creation should succeed");
     
     let mut log = log_result.unwrap();
-    let mut log_guard = log.lock().unwrap();
+    let mut log_guard = log.write().unwrap();
     
     // Append multiple records
     for i in 0..5 {
@@ -129,14 +138,17 @@ fn test_log_segment_rotation() {
             max_store_bytes: 100, // Very small to force rotation
             max_index_bytes: 100,
             initial_offset: 0,
+            index_interval_bytes: 0,
+            max_records_per_segment: 0,
         },
+        ..Default::default()
     };
     
     let log_result = Log::new(test_dir.to_string(), config);
     assert!(log_result.is_ok(), "Log creation should succeed");
     
     let mut log = log_result.unwrap();
-    let mut log_guard = log.lock().unwrap();
+    let mut log_guard = log.write().unwrap();
     
     // Append records until we force segment rotation
     for i in 0..10 {
@@ -173,14 +185,17 @@ fn test_log_invalid_offset() {
             max_store_bytes: 1024,
             max_index_bytes: 1024,
             initial_offset: 0,
+            index_interval_bytes: 0,
+            max_records_per_segment: 0,
         },
+        ..Default::default()
     };
     
     let log_result = Log::new(test_dir.to_string(), config);
     assert!(log_result.is_ok(), "Log creation should succeed");
     
     let mut log = log_result.unwrap();
-    let mut log_guard = log.lock().unwrap();
+    let log_guard = log.read().unwrap();
     
     // Try to read from non-existent offset
     let read_result = log_guard.read(999);
@@ -1,8 +1,12 @@
+use futures::StreamExt;
+use prost::Message;
 use std::fs;
 use std::sync::{Arc, Mutex};
+use walrus::log::commit::GroupCommit;
 use walrus::log::log::Log;
-use walrus::log::config;
+use walrus::log::{config, encryption};
 use walrus::log::segment::Record;
+use walrus::log::error::LogError;
 
 const TEST_BASE_DIR: &str = "/tmp/walrus_tests";
 
@@ -23,7 +27,10 @@ fn create_test_config(max_store_bytes: u64, max_index_bytes: u64) -> config::Con
             max_store_bytes,
             max_index_bytes,
             initial_offset: 0,
+            index_interval_bytes: 0,
+            max_records_per_segment: 0,
         },
+        ..Default::default()
     }
 }
 
@@ -37,7 +44,7 @@ fn test_wal_basic_operations() {
     assert!(log_result.is_ok(), "Log creation should succeed");
     
     let log = log_result.unwrap();
-    let mut log_guard = log.lock().unwrap();
+    let mut log_guard = log.write().unwrap();
     
     // Test append
     let mut record = Record::default();
@@ -69,7 +76,7 @@ fn test_wal_multiple_records() {
     assert!(log_result.is_ok(), "Log creation should succeed");
     
     let log = log_result.unwrap();
-    let mut log_guard = log.lock().unwrap();
+    let mut log_guard = log.write().unwrap();
     
     // Append multiple records
     let test_messages = vec![
@@ -114,7 +121,7 @@ fn test_wal_segment_rotation() {
     assert!(log_result.is_ok(), "Log creation should succeed");
     
     let log = log_result.unwrap();
-    let mut log_guard = log.lock().unwrap();
+    let mut log_guard = log.write().unwrap();
     
     // Append records until we force segment rotation
     for i in 0..10 {
@@ -150,7 +157,7 @@ fn test_wal_recovery() {
         assert!(log_result.is_ok(), "Log creation should succeed");
         
         let log = log_result.unwrap();
-        let mut log_guard = log.lock().unwrap();
+        let mut log_guard = log.write().unwrap();
         
         for i in 0..5 {
             let mut record = Record::default();
@@ -170,7 +177,7 @@ fn test_wal_recovery() {
         assert!(log_result.is_ok(), "Log recovery should succeed");
         
         let log = log_result.unwrap();
-        let mut log_guard = log.lock().unwrap();
+        let mut log_guard = log.write().unwrap();
         
         // Verify existing records are still there
         for i in 0..5 {
@@ -217,7 +224,7 @@ fn test_wal_error_handling() {
     assert!(log_result.is_ok(), "Log creation should succeed");
     
     let log = log_result.unwrap();
-    let mut log_guard = log.lock().unwrap();
+    let log_guard = log.read().unwrap();
     
     // Try to read from non-existent offset
     let read_result = log_guard.read(999);
@@ -244,7 +251,7 @@ fn test_wal_concurrent_access() {
     // Test that multiple threads can access the log safely
     let log_clone = log.clone();
     let handle = std::thread::spawn(move || {
-        let mut log_guard = log_clone.lock().unwrap();
+        let mut log_guard = log_clone.write().unwrap();
         
         for i in 0..5 {
             let mut record = Record::default();
@@ -258,7 +265,7 @@ fn test_wal_concurrent_access() {
     
     // Main thread also accesses the log
     {
-        let mut log_guard = log.lock().unwrap();
+        let mut log_guard = log.write().unwrap();
         
         for i in 0..5 {
             let mut record = Record::default();
@@ -273,7 +280,7 @@ fn test_wal_concurrent_access() {
     handle.join().unwrap();
     
     // Verify all records were written
-    let mut log_guard = log.lock().unwrap();
+    let log_guard = log.read().unwrap();
     for i in 0..10 {
         let read_result = log_guard.read(i);
         assert!(read_result.is_ok(), "Read {} should succeed", i);
@@ -294,7 +301,7 @@ fn test_wal_large_data() {
     assert!(log_result.is_ok(), "Log creation should succeed");
     
     let log = log_result.unwrap();
-    let mut log_guard = log.lock().unwrap();
+    let mut log_guard = log.write().unwrap();
     
     // Test with larger data
     let large_message = "x".repeat(1000); // 1KB message
@@ -329,7 +336,7 @@ fn test_wal_cleanup() {
     assert!(log_result.is_ok(), "Log creation should succeed");
     
     let log = log_result.unwrap();
-    let mut log_guard = log.lock().unwrap();
+    let mut log_guard = log.write().unwrap();
     
     // Add some data
     for i in 0..5 {
@@ -362,7 +369,7 @@ fn test_wal_performance() {
     assert!(log_result.is_ok(), "Log creation should succeed");
     
     let log = log_result.unwrap();
-    let mut log_guard = log.lock().unwrap();
+    let mut log_guard = log.write().unwrap();
     
     let start_time = std::time::Instant::now();
     
@@ -392,3 +399,1722 @@ fn test_wal_performance() {
     drop(log_guard);
     cleanup_test_env(&test_dir);
 }
+
+#[test]
+fn test_wal_truncate_keeps_held_slices_readable() {
+    let test_dir = setup_test_env("truncate_held_slices");
+    let config = create_test_config(16384, 1024);
+
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    let mut log_guard = log.write().unwrap();
+    for i in 0..400 {
+        let mut record = Record::default();
+        record.value = format!("Held slice message {}", i).into_bytes();
+        log_guard.append(&mut record).unwrap();
+    }
+    assert_eq!(segment_files(&test_dir), 2);
+
+    // A slice straight out of the first segment's mapping, a few pages into
+    // it, held across a truncate that cuts that segment right back
+    let held = log_guard.read_bytes(289).unwrap();
+    log_guard.truncate(1).unwrap();
+    assert_eq!(segment_files(&test_dir), 1);
+    assert!(log_guard.read(1).is_err());
+
+    // Every byte of the slice is still there to be read
+    let record = Record::decode(held).unwrap();
+    assert_eq!(record.offset, 289);
+    assert_eq!(record.value, b"Held slice message 289".to_vec());
+
+    // The segment carries on from the cut
+    let mut record = Record::default();
+    record.value = b"After the cut".to_vec();
+    assert_eq!(log_guard.append(&mut record).unwrap(), 1);
+    assert_eq!(log_guard.read(0).unwrap().value, b"Held slice message 0".to_vec());
+    assert_eq!(log_guard.read(1).unwrap().value, b"After the cut".to_vec());
+
+    // The same again, with the first segment unsealed by a truncate that
+    // leaves it whole before the one that cuts it back
+    for i in 2..400 {
+        let mut record = Record::default();
+        record.value = format!("Held slice message {}", i).into_bytes();
+        log_guard.append(&mut record).unwrap();
+    }
+    assert_eq!(segment_files(&test_dir), 2);
+    let held = log_guard.read_bytes(289).unwrap();
+    let second = *log_guard.segments.keys().last().unwrap();
+    log_guard.truncate(second).unwrap();
+    log_guard.truncate(1).unwrap();
+    assert_eq!(segment_files(&test_dir), 1);
+
+    let record = Record::decode(held).unwrap();
+    assert_eq!(record.offset, 289);
+    assert_eq!(record.value, b"Held slice message 289".to_vec());
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_failed_truncate_leaves_log_intact() {
+    let test_dir = setup_test_env("failed_truncate");
+    let mut config = create_test_config(1024, 1024);
+    config.segment.max_records_per_segment = 3;
+
+    {
+        let log = Log::new(test_dir.clone(), config.clone()).unwrap();
+        let mut log_guard = log.write().unwrap();
+        for i in 0..9 {
+            let mut record = Record::default();
+            record.value = format!("Truncate test message {}", i).into_bytes();
+            log_guard.append(&mut record).unwrap();
+        }
+        log_guard.close().unwrap();
+    }
+    assert_eq!(segment_files(&test_dir), 3);
+
+    // Damage the record the cut would land on, in the middle of the first
+    // segment's three equally sized frames
+    let store_path = format!("{}/0.store", test_dir);
+    let mut bytes = fs::read(&store_path).unwrap();
+    let middle = bytes.len() / 2;
+    bytes[middle] ^= 0xff;
+    fs::write(&store_path, bytes).unwrap();
+
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    let mut log_guard = log.write().unwrap();
+    let err = log_guard.truncate(1).unwrap_err();
+    assert!(matches!(err, LogError::Corrupt(_)), "{}", err);
+
+    // Nothing was removed on the way to finding out the cut cannot be made
+    assert_eq!(segment_files(&test_dir), 3);
+    assert_eq!(log_guard.next_offset(), 9);
+    for i in [0, 2, 3, 8] {
+        assert_eq!(log_guard.read(i).unwrap().value, format!("Truncate test message {}", i).into_bytes());
+    }
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_detects_corruption() {
+    let test_dir = setup_test_env("corruption");
+    let config = create_test_config(1024, 1024);
+
+    {
+        let log = Log::new(test_dir.clone(), config.clone()).unwrap();
+        let mut log_guard = log.write().unwrap();
+
+        for i in 0..3 {
+            let mut record = Record::default();
+            record.value = format!("Corruption test message {}", i).into_bytes();
+            log_guard.append(&mut record).unwrap();
+        }
+
+        log_guard.close().unwrap();
+    }
+
+    // The three frames are the same size, so the middle byte of the store
+    // falls inside the payload of the second record
+    let store_path = format!("{}/0.store", test_dir);
+    let mut bytes = fs::read(&store_path).unwrap();
+    let middle = bytes.len() / 2;
+    bytes[middle] ^= 0xff;
+    fs::write(&store_path, bytes).unwrap();
+
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    let log_guard = log.read().unwrap();
+
+    // Untouched records still read back fine
+    for i in [0, 2] {
+        let read_record = log_guard.read(i).unwrap();
+        assert_eq!(read_record.value, format!("Corruption test message {}", i).into_bytes());
+    }
+
+    // The damaged record is reported as corrupt rather than as a decode failure
+    let err = log_guard.read(1).expect_err("Reading a corrupt record should fail");
+    assert!(matches!(err, LogError::Corrupt(_)), "Expected a corrupt record, got: {}", err);
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_recovers_torn_tail() {
+    let test_dir = setup_test_env("torn_tail");
+    let config = create_test_config(1024, 1024);
+
+    {
+        let log = Log::new(test_dir.clone(), config.clone()).unwrap();
+        let mut log_guard = log.write().unwrap();
+
+        for i in 0..5 {
+            let mut record = Record::default();
+            record.value = format!("Torn tail message {}", i).into_bytes();
+            log_guard.append(&mut record).unwrap();
+        }
+
+        log_guard.close().unwrap();
+    }
+
+    // Simulate a crash part way through writing the final frame
+    let store_path = format!("{}/0.store", test_dir);
+    let store_len = fs::metadata(&store_path).unwrap().len();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&store_path)
+        .unwrap()
+        .set_len(store_len - 3)
+        .unwrap();
+
+    {
+        let log = Log::new(test_dir.clone(), config.clone()).unwrap();
+        let mut log_guard = log.write().unwrap();
+
+        for i in 0..4 {
+            let read_record = log_guard.read(i).unwrap();
+            assert_eq!(read_record.value, format!("Torn tail message {}", i).into_bytes());
+        }
+        assert!(log_guard.read(4).is_err(), "Torn record should have been discarded");
+
+        // The discarded offset is handed out again
+        let mut record = Record::default();
+        record.value = b"Rewritten message 4".to_vec();
+        assert_eq!(log_guard.append(&mut record).unwrap(), 4);
+
+        log_guard.close().unwrap();
+    }
+
+    // A clean reopen finds nothing left to discard
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    let log_guard = log.read().unwrap();
+    assert_eq!(log_guard.read(4).unwrap().value, b"Rewritten message 4");
+    assert!(log_guard.read(5).is_err());
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_recovers_index_past_store() {
+    let test_dir = setup_test_env("index_past_store");
+    let config = create_test_config(1024, 1024);
+
+    {
+        let log = Log::new(test_dir.clone(), config.clone()).unwrap();
+        let mut log_guard = log.write().unwrap();
+
+        for i in 0..3 {
+            let mut record = Record::default();
+            record.value = format!("Buffered message {}", i).into_bytes();
+            log_guard.append(&mut record).unwrap();
+        }
+
+        log_guard.close().unwrap();
+    }
+
+    // Simulate the write buffer never reaching the store while the index did
+    fs::OpenOptions::new()
+        .write(true)
+        .open(format!("{}/0.store", test_dir))
+        .unwrap()
+        .set_len(0)
+        .unwrap();
+
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    let mut log_guard = log.write().unwrap();
+    assert!(log_guard.read(0).is_err(), "Unwritten records should have been discarded");
+
+    let mut record = Record::default();
+    record.value = b"First durable message".to_vec();
+    assert_eq!(log_guard.append(&mut record).unwrap(), 0);
+    assert_eq!(log_guard.read(0).unwrap().value, b"First durable message");
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_rejects_incompatible_index() {
+    let test_dir = setup_test_env("incompatible_index");
+    let config = create_test_config(1024, 1024);
+
+    {
+        let log = Log::new(test_dir.clone(), config.clone()).unwrap();
+        let mut log_guard = log.write().unwrap();
+
+        let mut record = Record::default();
+        record.value = b"Indexed message".to_vec();
+        log_guard.append(&mut record).unwrap();
+
+        log_guard.close().unwrap();
+    }
+
+    // Bump the format version stored after the magic bytes
+    let index_path = format!("{}/0.index", test_dir);
+    let mut bytes = fs::read(&index_path).unwrap();
+    assert_eq!(&bytes[..4], b"WIDX");
+    bytes[4..8].copy_from_slice(&99u32.to_be_bytes());
+    fs::write(&index_path, bytes).unwrap();
+
+    let result = Log::new(test_dir.clone(), config);
+    assert!(result.is_err(), "Opening an index from another format version should fail");
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_upgrades_legacy_segments() {
+    let test_dir = setup_test_env("legacy_upgrade");
+    let config = create_test_config(1024, 1024);
+
+    // Lay out a segment the way the headerless format did:
+    // [len: u64][payload] store frames and bare 12 byte index entries
+    let mut store_bytes = Vec::new();
+    let mut index_bytes = Vec::new();
+    for i in 0..3u64 {
+        let mut record = Record::default();
+        record.value = format!("Legacy message {}", i).into_bytes();
+        record.offset = i;
+        let payload = record.encode_to_vec();
+
+        index_bytes.extend_from_slice(&(i as u32).to_be_bytes());
+        index_bytes.extend_from_slice(&(store_bytes.len() as u64).to_be_bytes());
+        store_bytes.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        store_bytes.extend_from_slice(&payload);
+    }
+    index_bytes.resize(1024, 0);
+    fs::write(format!("{}/0.store", test_dir), &store_bytes).unwrap();
+    fs::write(format!("{}/0.index", test_dir), &index_bytes).unwrap();
+
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    let mut log_guard = log.write().unwrap();
+
+    for i in 0..3 {
+        let read_record = log_guard.read(i).unwrap();
+        assert_eq!(read_record.value, format!("Legacy message {}", i).into_bytes());
+    }
+
+    // Appends continue from where the legacy segment left off
+    let mut record = Record::default();
+    record.value = b"Upgraded message".to_vec();
+    assert_eq!(log_guard.append(&mut record).unwrap(), 3);
+    assert_eq!(log_guard.read(3).unwrap().value, b"Upgraded message");
+
+    // Both files now carry a header
+    let store_bytes = fs::read(format!("{}/0.store", test_dir)).unwrap();
+    let index_bytes = fs::read(format!("{}/0.index", test_dir)).unwrap();
+    assert_eq!(&store_bytes[..4], b"WSTR");
+    assert_eq!(&index_bytes[..4], b"WIDX");
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_upgrades_v2_segments() {
+    let test_dir = setup_test_env("v2_upgrade");
+    let config = create_test_config(1024, 1024);
+
+    // Lay out a segment the way format version 2 did, with headers but
+    // [len: u64][crc32c of the payload: u32][payload] store frames. Offset 2
+    // was compacted away, and the last frame was torn by a crash.
+    let mut store_bytes = b"WSTR".to_vec();
+    store_bytes.extend_from_slice(&2u32.to_be_bytes());
+    let mut index_bytes = b"WIDX".to_vec();
+    index_bytes.extend_from_slice(&2u32.to_be_bytes());
+    index_bytes.extend_from_slice(&3u64.to_be_bytes());
+    for i in [0u64, 1, 3] {
+        let mut record = Record::default();
+        record.value = format!("Version 2 message {}", i).into_bytes();
+        record.offset = i;
+        let payload = record.encode_to_vec();
+
+        index_bytes.extend_from_slice(&(i as u32).to_be_bytes());
+        index_bytes.extend_from_slice(&(store_bytes.len() as u64).to_be_bytes());
+        store_bytes.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        store_bytes.extend_from_slice(&crc32c::crc32c(&payload).to_be_bytes());
+        store_bytes.extend_from_slice(&payload);
+    }
+    store_bytes.extend_from_slice(&100u64.to_be_bytes());
+    store_bytes.extend_from_slice(b"torn");
+    index_bytes.resize(1024, 0);
+    fs::write(format!("{}/0.store", test_dir), &store_bytes).unwrap();
+    fs::write(format!("{}/0.index", test_dir), &index_bytes).unwrap();
+
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    let mut log_guard = log.write().unwrap();
+
+    for i in [0, 1, 3] {
+        assert_eq!(log_guard.read(i).unwrap().value, format!("Version 2 message {}", i).into_bytes());
+    }
+    assert!(matches!(log_guard.read(2), Err(LogError::OffsetRemoved(2))));
+    assert_eq!(log_guard.next_offset(), 4);
+
+    let mut record = Record::default();
+    record.value = b"Upgraded message".to_vec();
+    assert_eq!(log_guard.append(&mut record).unwrap(), 4);
+    assert_eq!(log_guard.read(4).unwrap().value, b"Upgraded message");
+
+    // Every file is now at the current version
+    for file in ["0.store", "0.index", "0.timeindex"] {
+        let bytes = fs::read(format!("{}/{}", test_dir, file)).unwrap();
+        assert_eq!(&bytes[4..8], &3u32.to_be_bytes(), "{} should be at version 3", file);
+    }
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}
+
+fn segment_files(test_dir: &str) -> usize {
+    fs::read_dir(test_dir)
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension().is_some_and(|ext| ext == "store"))
+        .count()
+}
+
+#[test]
+fn test_wal_retention_by_size() {
+    let test_dir = setup_test_env("retention_size");
+    let mut config = create_test_config(100, 1024);
+    config.retention.max_bytes = 300;
+
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    let mut log_guard = log.write().unwrap();
+
+    for i in 0..20 {
+        let mut record = Record::default();
+        record.value = format!("Retention message {} padded out to fill segments", i).into_bytes();
+        log_guard.append(&mut record).unwrap();
+    }
+
+    // The oldest segments are gone, the newest records are still readable
+    assert!(log_guard.read(0).is_err(), "Oldest record should have been removed");
+    assert!(log_guard.read(19).is_ok(), "Newest record should be retained");
+    assert!(segment_files(&test_dir) <= 4, "Old segment files should have been deleted");
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_retention_by_offset_and_age() {
+    let test_dir = setup_test_env("retention_offset_age");
+    let config = create_test_config(100, 1024);
+
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    let mut log_guard = log.write().unwrap();
+
+    for i in 0..10 {
+        let mut record = Record::default();
+        record.value = format!("Retention message {} padded out to fill segments", i).into_bytes();
+        log_guard.append(&mut record).unwrap();
+    }
+    let segments_before = segment_files(&test_dir);
+
+    // Nothing is removed without a policy
+    assert_eq!(log_guard.enforce_retention().unwrap(), 0);
+
+    // Only segments that lie entirely below the minimum offset are dropped
+    log_guard.config.retention.min_offset = 3;
+    assert!(log_guard.enforce_retention().unwrap() > 0);
+    assert!(log_guard.read(0).is_err(), "Record below the minimum offset should be removed");
+    assert!(log_guard.read(3).is_ok(), "Record at the minimum offset should be retained");
+    assert!(segment_files(&test_dir) < segments_before);
+
+    // Every closed segment is older than the age limit, the active one stays
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    log_guard.config.retention.max_age_ms = 1;
+    log_guard.enforce_retention().unwrap();
+    assert_eq!(segment_files(&test_dir), 1);
+    assert!(log_guard.read(9).is_ok(), "Records in the active segment should be retained");
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_retention_by_age_survives_compaction() {
+    let test_dir = setup_test_env("retention_age_compaction");
+    let mut config = create_test_config(1024 * 1024, 1024);
+    config.segment.max_records_per_segment = 4;
+    config.compaction.tombstone_retention_ms = 60 * 60 * 1000;
+
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+    let hour = 60 * 60 * 1000;
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    let mut log_guard = log.write().unwrap();
+
+    // A day old segment, then a fresh one with records to compact away,
+    // then the active segment
+    for (i, timestamp) in [now - 24 * hour; 4].into_iter().chain([now; 5]).enumerate() {
+        let mut record = Record::default();
+        record.key = if i < 4 { format!("old-{}", i) } else { format!("key-{}", i % 2) }.into_bytes();
+        record.value = format!("Aged message {}", i).into_bytes();
+        record.timestamp = timestamp;
+        log_guard.append(&mut record).unwrap();
+    }
+    assert_eq!(segment_files(&test_dir), 3);
+
+    // Compaction rewrites the closed segments' files, which does not make
+    // their records any younger
+    drop(log_guard);
+    assert!(Log::compact(&log).unwrap() > 0);
+    let mut log_guard = log.write().unwrap();
+    log_guard.config.retention.max_age_ms = hour;
+    assert_eq!(log_guard.enforce_retention().unwrap(), 1);
+    assert_eq!(log_guard.segments.keys().copied().collect::<Vec<_>>(), vec![4, 8]);
+    assert!(log_guard.read(3).is_err(), "Records older than the age limit should be removed");
+    assert!(log_guard.read(7).is_ok(), "Records newer than the age limit should be retained");
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_truncate() {
+    let test_dir = setup_test_env("truncate");
+    let config = create_test_config(100, 1024);
+
+    {
+        let log = Log::new(test_dir.clone(), config.clone()).unwrap();
+        let mut log_guard = log.write().unwrap();
+
+        for i in 0..10 {
+            let mut record = Record::default();
+            record.value = format!("Original message {} padded out to fill segments", i).into_bytes();
+            log_guard.append(&mut record).unwrap();
+        }
+
+        // Cut inside an earlier segment, dropping every later segment
+        log_guard.truncate(4).unwrap();
+        assert!(log_guard.read(3).is_ok(), "Records before the cut should remain");
+        assert!(log_guard.read(4).is_err(), "Record at the cut should be removed");
+        assert!(log_guard.read(9).is_err(), "Records after the cut should be removed");
+
+        // The cut offset is handed out again
+        for i in 4..6 {
+            let mut record = Record::default();
+            record.value = format!("Replacement message {}", i).into_bytes();
+            assert_eq!(log_guard.append(&mut record).unwrap(), i);
+        }
+
+        // Truncating past the end is a no-op
+        log_guard.truncate(100).unwrap();
+        assert!(log_guard.read(5).is_ok());
+
+        log_guard.close().unwrap();
+    }
+
+    // The truncated log reopens as it was left
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    let mut log_guard = log.write().unwrap();
+    assert_eq!(
+        log_guard.read(3).unwrap().value,
+        b"Original message 3 padded out to fill segments".to_vec()
+    );
+    assert_eq!(log_guard.read(5).unwrap().value, b"Replacement message 5".to_vec());
+    assert!(log_guard.read(6).is_err());
+
+    // Truncating everything starts again from the cut point
+    log_guard.truncate(0).unwrap();
+    assert!(log_guard.read(0).is_err());
+    let mut record = Record::default();
+    record.value = b"Fresh start".to_vec();
+    assert_eq!(log_guard.append(&mut record).unwrap(), 0);
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_offset_range() {
+    let test_dir = setup_test_env("offset_range");
+    let config = create_test_config(100, 1024);
+
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    let mut log_guard = log.write().unwrap();
+
+    // A new log holds nothing
+    assert_eq!(log_guard.lowest_offset(), None);
+    assert_eq!(log_guard.highest_offset(), None);
+
+    for i in 0..10 {
+        let mut record = Record::default();
+        record.value = format!("Range message {} padded out to fill segments", i).into_bytes();
+        log_guard.append(&mut record).unwrap();
+    }
+    assert_eq!(log_guard.lowest_offset(), Some(0));
+    assert_eq!(log_guard.highest_offset(), Some(9));
+
+    // Retention moves the lower bound up, truncation the upper bound down
+    log_guard.config.retention.min_offset = 3;
+    log_guard.enforce_retention().unwrap();
+    let lowest = log_guard.lowest_offset().unwrap();
+    assert!(lowest > 0 && lowest <= 3);
+    assert!(log_guard.read(lowest).is_ok());
+    assert!(log_guard.read(lowest - 1).is_err());
+
+    log_guard.truncate(7).unwrap();
+    assert_eq!(log_guard.highest_offset(), Some(6));
+
+    // Cutting away every record leaves an empty log
+    log_guard.truncate(lowest).unwrap();
+    assert_eq!(log_guard.lowest_offset(), None);
+    assert_eq!(log_guard.highest_offset(), None);
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_append_batch() {
+    let test_dir = setup_test_env("append_batch");
+    let config = create_test_config(200, 1024);
+
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    let mut log_guard = log.write().unwrap();
+
+    let mut record = Record::default();
+    record.value = b"Single message".to_vec();
+    log_guard.append(&mut record).unwrap();
+
+    // Big enough to roll over several segments part way through
+    let mut batch: Vec<Record> = (0..20)
+        .map(|i| {
+            let mut record = Record::default();
+            record.value = format!("Batch message {} padded out to fill segments", i).into_bytes();
+            record
+        })
+        .collect();
+
+    let offsets = log_guard.append_batch(&mut batch).unwrap();
+    assert_eq!(offsets, 1..21);
+    assert!(segment_files(&test_dir) > 1, "Batch should have rolled segments");
+
+    for (i, offset) in offsets.enumerate() {
+        assert_eq!(batch[i].offset, offset);
+        let read_record = log_guard.read(offset).unwrap();
+        assert_eq!(read_record.value, format!("Batch message {} padded out to fill segments", i).into_bytes());
+    }
+
+    // An empty batch writes nothing
+    assert_eq!(log_guard.append_batch(&mut []).unwrap(), 21..21);
+
+    let mut record = Record::default();
+    record.value = b"After the batch".to_vec();
+    assert_eq!(log_guard.append(&mut record).unwrap(), 21);
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_failed_batch_is_rolled_back() {
+    let test_dir = setup_test_env("failed_batch");
+    let mut config = create_test_config(1024, 1024);
+    config.segment.max_records_per_segment = 3;
+
+    let log = Log::new(test_dir.clone(), config.clone()).unwrap();
+    let mut log_guard = log.write().unwrap();
+    let record = |i: u64| Record { value: format!("Batch record {}", i).into_bytes(), ..Default::default() };
+    log_guard.append_batch(&mut [record(0), record(1)]).unwrap();
+
+    // The batch fills the first segment and then cannot roll to the next,
+    // since its store file cannot be created
+    let blocker = format!("{}/3.store", test_dir);
+    fs::create_dir(&blocker).unwrap();
+    let mut batch: Vec<Record> = (2..6).map(record).collect();
+    assert!(log_guard.append_batch(&mut batch).is_err());
+
+    // The record that did fit was taken back out
+    assert_eq!(log_guard.next_offset(), 2);
+    assert!(matches!(log_guard.read(2), Err(LogError::OffsetOutOfRange { .. })));
+
+    // Once the segment can be created the batch goes in whole, at the same offsets
+    fs::remove_dir(&blocker).unwrap();
+    let mut batch: Vec<Record> = (2..6).map(record).collect();
+    assert_eq!(log_guard.append_batch(&mut batch).unwrap(), 2..6);
+    log_guard.close().unwrap();
+    drop(log_guard);
+    drop(log);
+
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    let log_guard = log.read().unwrap();
+    assert_eq!(log_guard.next_offset(), 6);
+    for i in 0..6 {
+        assert_eq!(log_guard.read(i).unwrap().value, record(i).value);
+    }
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_durability_modes() {
+    let modes = vec![
+        ("durability_every_write", config::Durability::EveryWrite),
+        ("durability_periodic", config::Durability::Periodic { bytes: 64, interval_ms: 10 }),
+        ("durability_os", config::Durability::OsManaged),
+    ];
+
+    for (name, durability) in modes {
+        let test_dir = setup_test_env(name);
+        let mut config = create_test_config(1024, 1024);
+        config.durability = durability;
+
+        {
+            let log = Log::new(test_dir.clone(), config.clone()).unwrap();
+            let mut log_guard = log.write().unwrap();
+
+            for i in 0..10 {
+                let mut record = Record::default();
+                record.value = format!("Durable message {}", i).into_bytes();
+                log_guard.append(&mut record).unwrap();
+            }
+            log_guard.sync().unwrap();
+
+            // Every append is visible to other readers of the file straight
+            // away, without waiting for a read to flush the write buffer
+            let store_len = fs::metadata(format!("{}/0.store", test_dir)).unwrap().len();
+            assert!(store_len > 10 * 16, "{}: appends should reach the file", name);
+        }
+
+        let log = Log::new(test_dir.clone(), config).unwrap();
+        let log_guard = log.read().unwrap();
+        for i in 0..10 {
+            let read_record = log_guard.read(i).unwrap();
+            assert_eq!(read_record.value, format!("Durable message {}", i).into_bytes());
+        }
+
+        drop(log_guard);
+        cleanup_test_env(&test_dir);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_wal_group_commit() {
+    let test_dir = setup_test_env("group_commit");
+    let mut config = create_test_config(64 * 1024, 1024);
+    config.durability = config::Durability::EveryWrite;
+
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    // A window long enough for every writer to turn up in it
+    let committer = GroupCommit::new(log.clone(), std::time::Duration::from_millis(500), 64);
+
+    let mut handles = Vec::new();
+    for i in 0..50 {
+        let committer = committer.clone();
+        handles.push(tokio::spawn(async move {
+            let mut record = Record::default();
+            record.value = format!("Group commit message {}", i).into_bytes();
+            (i, committer.append(record).await.unwrap())
+        }));
+    }
+
+    let mut offsets = Vec::new();
+    for handle in handles {
+        offsets.push(handle.await.unwrap());
+    }
+
+    // Every writer got its own offset, and it holds that writer's record
+    let mut assigned: Vec<u64> = offsets.iter().map(|(_, offset)| *offset).collect();
+    assigned.sort();
+    assert_eq!(assigned, (0..50).collect::<Vec<u64>>());
+
+    // All of them went in with one append, and so one fsync
+    assert_eq!(committer.commits(), 1);
+
+    let log_guard = log.read().unwrap();
+    for (i, offset) in offsets {
+        let read_record = log_guard.read(offset).unwrap();
+        assert_eq!(read_record.value, format!("Group commit message {}", i).into_bytes());
+    }
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}
+
+#[tokio::test]
+async fn test_wal_reader() {
+    let test_dir = setup_test_env("reader");
+    let config = create_test_config(200, 1024);
+
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    let mut log_guard = log.write().unwrap();
+
+    for i in 0..30 {
+        let mut record = Record::default();
+        record.value = format!("Reader message {}", i).into_bytes();
+        log_guard.append(&mut record).unwrap();
+    }
+    assert!(segment_files(&test_dir) > 1, "Records should span several segments");
+
+    // From the start, across every segment
+    let records: Vec<Record> = log_guard.reader(0).unwrap().map(|r| r.unwrap()).collect();
+    assert_eq!(records.len(), 30);
+    for (i, record) in records.iter().enumerate() {
+        assert_eq!(record.offset, i as u64);
+        assert_eq!(record.value, format!("Reader message {}", i).into_bytes());
+    }
+
+    // From part way through a segment
+    let offsets: Vec<u64> = log_guard.reader(17).unwrap().map(|r| r.unwrap().offset).collect();
+    assert_eq!(offsets, (17..30).collect::<Vec<u64>>());
+
+    // Only covers what was there when the reader was created
+    let reader = log_guard.reader(28).unwrap();
+    let mut record = Record::default();
+    record.value = b"Written after the reader".to_vec();
+    log_guard.append(&mut record).unwrap();
+    assert_eq!(reader.count(), 2);
+
+    // Past the end is empty
+    assert_eq!(log_guard.reader(31).unwrap().count(), 0);
+
+    // The stream gives the same records
+    let stream = log_guard.reader(5).unwrap().into_stream();
+    drop(log_guard);
+    let streamed: Vec<u64> = stream.map(|r| r.unwrap().offset).collect().await;
+    assert_eq!(streamed, (5..31).collect::<Vec<u64>>());
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_read_across_many_segments() {
+    let test_dir = setup_test_env("read_many_segments");
+    // Small enough that every few records start a new segment
+    let mut config = create_test_config(64, 1024);
+    config.segment.initial_offset = 100;
+
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    let mut log_guard = log.write().unwrap();
+
+    for i in 0..200 {
+        let mut record = Record::default();
+        record.value = format!("Segment message {}", i).into_bytes();
+        log_guard.append(&mut record).unwrap();
+    }
+    assert!(segment_files(&test_dir) > 50, "Records should span many segments");
+
+    // Every offset is found in its own segment, in any order
+    for i in (0..200).rev().step_by(7).chain(0..200) {
+        let read_record = log_guard.read(100 + i).unwrap();
+        assert_eq!(read_record.offset, 100 + i);
+        assert_eq!(read_record.value, format!("Segment message {}", i).into_bytes());
+    }
+
+    for offset in [99, 300] {
+        match log_guard.read(offset) {
+            Err(LogError::OffsetOutOfRange { start, end, .. }) => assert_eq!((start, end), (100, 300)),
+            other => panic!("Offset {} should be out of range, got {:?}", offset, other.map(|r| r.offset)),
+        }
+    }
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_concurrent_readers() {
+    let test_dir = setup_test_env("concurrent_readers");
+    let config = create_test_config(512, 1024);
+
+    let log = Log::new(test_dir.clone(), config).unwrap();
+
+    let writer_log = log.clone();
+    let writer = std::thread::spawn(move || {
+        for i in 0..200 {
+            let mut record = Record::default();
+            record.value = format!("Concurrent message {}", i).into_bytes();
+            writer_log.write().unwrap().append(&mut record).unwrap();
+        }
+    });
+
+    // Readers share the log with each other while the writer keeps appending
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let reader_log = log.clone();
+            std::thread::spawn(move || {
+                let mut seen = 0;
+                while seen < 200 {
+                    let log_guard = reader_log.read().unwrap();
+                    seen = log_guard.next_offset();
+                    for i in 0..seen {
+                        let read_record = log_guard.read(i).unwrap();
+                        assert_eq!(read_record.value, format!("Concurrent message {}", i).into_bytes());
+                    }
+                }
+            })
+        })
+        .collect();
+
+    writer.join().unwrap();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_reader_outlives_retention() {
+    let test_dir = setup_test_env("reader_outlives_retention");
+    let config = create_test_config(100, 1024);
+
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    let mut log_guard = log.write().unwrap();
+    for i in 0..20 {
+        let mut record = Record::default();
+        record.value = format!("Replayed message {}", i).into_bytes();
+        log_guard.append(&mut record).unwrap();
+    }
+
+    // Retention deletes the segments the reader has not got to yet
+    let reader = log_guard.reader(0).unwrap();
+    log_guard.config.retention.min_offset = 15;
+    assert!(log_guard.enforce_retention().unwrap() > 0);
+    assert!(log_guard.lowest_offset().unwrap() > 0);
+
+    // The reader still has them open and replays the log as it was
+    let replayed: Vec<Record> = reader.map(|r| r.unwrap()).collect();
+    assert_eq!(replayed.len(), 20);
+    for (i, record) in replayed.iter().enumerate() {
+        assert_eq!(record.value, format!("Replayed message {}", i).into_bytes());
+    }
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_sealed_segment_reads() {
+    let test_dir = setup_test_env("sealed_segment_reads");
+    let config = create_test_config(200, 1024);
+
+    let log = Log::new(test_dir.clone(), config.clone()).unwrap();
+    let mut log_guard = log.write().unwrap();
+
+    for i in 0..20 {
+        let mut record = Record::default();
+        record.value = format!("Sealed message {} padded out to fill segments", i).into_bytes();
+        log_guard.append(&mut record).unwrap();
+    }
+    assert!(segment_files(&test_dir) > 1, "Records should span several segments");
+
+    // Records in a sealed segment are slices of the same mapping
+    let first = log_guard.read_bytes(0).unwrap();
+    let again = log_guard.read_bytes(0).unwrap();
+    assert_eq!(first.as_ptr(), again.as_ptr(), "Sealed reads should not copy");
+    assert_eq!(Record::decode(first).unwrap().value, b"Sealed message 0 padded out to fill segments");
+
+    // The active segment still reads through the file
+    let last = log_guard.read_bytes(19).unwrap();
+    assert_eq!(Record::decode(last).unwrap().offset, 19);
+
+    // Cutting back into a sealed segment makes it writable again
+    log_guard.truncate(3).unwrap();
+    let mut record = Record::default();
+    record.value = b"Written after truncation".to_vec();
+    assert_eq!(log_guard.append(&mut record).unwrap(), 3);
+    assert_eq!(log_guard.read(3).unwrap().value, b"Written after truncation");
+    assert_eq!(log_guard.read(2).unwrap().value, b"Sealed message 2 padded out to fill segments");
+
+    // Roll it over again
+    for i in 4..20 {
+        let mut record = Record::default();
+        record.value = format!("Sealed message {} padded out to fill segments", i).into_bytes();
+        log_guard.append(&mut record).unwrap();
+    }
+
+    // Segments loaded from disk are sealed too
+    log_guard.close().unwrap();
+    drop(log_guard);
+    drop(log);
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    let log_guard = log.read().unwrap();
+    let first = log_guard.read_bytes(0).unwrap();
+    assert_eq!(first.as_ptr(), log_guard.read_bytes(0).unwrap().as_ptr());
+    assert_eq!(log_guard.read(3).unwrap().value, b"Written after truncation");
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}
+
+// The shape Record had before key, headers and timestamp were added
+#[derive(Clone, PartialEq, prost::Message)]
+struct RecordWithoutMetadata {
+    #[prost(bytes = "vec", tag = "1")]
+    value: Vec<u8>,
+    #[prost(uint64, tag = "2")]
+    offset: u64,
+}
+
+#[test]
+fn test_wal_record_metadata() {
+    let test_dir = setup_test_env("record_metadata");
+    let config = create_test_config(1024, 1024);
+
+    let log = Log::new(test_dir.clone(), config.clone()).unwrap();
+    let mut log_guard = log.write().unwrap();
+
+    let mut record = Record::default();
+    record.value = b"Order placed".to_vec();
+    record.key = b"customer-42".to_vec();
+    record.headers.insert("trace-id".to_string(), "abc123".to_string());
+    record.headers.insert("content-type".to_string(), "text/plain".to_string());
+    record.timestamp = 1_700_000_000_000;
+    let offset = log_guard.append(&mut record).unwrap();
+
+    let mut plain = Record::default();
+    plain.value = b"No metadata".to_vec();
+    log_guard.append(&mut plain).unwrap();
+
+    log_guard.close().unwrap();
+    drop(log_guard);
+    drop(log);
+
+    // Everything survives a reopen
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    let log_guard = log.read().unwrap();
+    let read_record = log_guard.read(offset).unwrap();
+    assert_eq!(read_record.key, b"customer-42");
+    assert_eq!(read_record.headers.get("trace-id").map(String::as_str), Some("abc123"));
+    assert_eq!(read_record.headers.get("content-type").map(String::as_str), Some("text/plain"));
+    assert_eq!(read_record.timestamp, 1_700_000_000_000);
+
+    let read_plain = log_guard.read(offset + 1).unwrap();
+    assert!(read_plain.key.is_empty());
+    assert!(read_plain.headers.is_empty());
+
+    // Records encoded before the metadata existed still decode
+    let old = RecordWithoutMetadata { value: b"Old record".to_vec(), offset: 7 };
+    let decoded = Record::decode(&*old.encode_to_vec()).unwrap();
+    assert_eq!(decoded.value, b"Old record");
+    assert_eq!(decoded.offset, 7);
+    assert!(decoded.key.is_empty() && decoded.headers.is_empty() && decoded.timestamp == 0);
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_offset_for_timestamp() {
+    let test_dir = setup_test_env("offset_for_timestamp");
+    let config = create_test_config(200, 1024);
+
+    let log = Log::new(test_dir.clone(), config.clone()).unwrap();
+    let mut log_guard = log.write().unwrap();
+
+    assert_eq!(log_guard.offset_for_timestamp(0).unwrap(), None, "An empty log has no offsets");
+
+    // Several records share each timestamp, and the last one arrives out of order
+    let timestamps = [1000, 1000, 2000, 2000, 2000, 3000, 3000, 4000, 4000, 2500];
+    for (i, &timestamp) in timestamps.iter().enumerate() {
+        let mut record = Record::default();
+        record.value = format!("Timed message {} padded out to fill segments", i).into_bytes();
+        record.timestamp = timestamp;
+        log_guard.append(&mut record).unwrap();
+    }
+    assert!(segment_files(&test_dir) > 1, "Records should span several segments");
+
+    let expected = [(0, Some(0)), (1000, Some(0)), (1500, Some(2)), (2000, Some(2)), (2500, Some(5)), (4000, Some(7)), (4001, None)];
+    for &(timestamp, offset) in &expected {
+        assert_eq!(log_guard.offset_for_timestamp(timestamp).unwrap(), offset, "Lookup for {}", timestamp);
+    }
+
+    // Records without a timestamp are stamped when they are appended
+    let mut record = Record::default();
+    record.value = b"Stamped on append".to_vec();
+    let offset = log_guard.append(&mut record).unwrap();
+    assert!(log_guard.read(offset).unwrap().timestamp > 4000);
+    assert_eq!(log_guard.offset_for_timestamp(4001).unwrap(), Some(offset));
+
+    log_guard.truncate(offset).unwrap();
+    assert_eq!(log_guard.offset_for_timestamp(4001).unwrap(), None);
+
+    log_guard.close().unwrap();
+    drop(log_guard);
+    drop(log);
+
+    // The time indexes are rebuilt from the records if they go missing
+    for entry in fs::read_dir(&test_dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_some_and(|ext| ext == "timeindex") {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    let log_guard = log.read().unwrap();
+    for &(timestamp, offset) in &expected {
+        assert_eq!(log_guard.offset_for_timestamp(timestamp).unwrap(), offset, "Rebuilt lookup for {}", timestamp);
+    }
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_sparse_index() {
+    let test_dir = setup_test_env("sparse_index");
+    // Index files start out with room for only eight entries
+    let mut config = create_test_config(4096, 112);
+    config.segment.index_interval_bytes = 256;
+
+    {
+        let log = Log::new(test_dir.clone(), config.clone()).unwrap();
+        let mut log_guard = log.write().unwrap();
+
+        for i in 0..40 {
+            let mut record = Record::default();
+            record.value = format!("Sparse message {}", i).into_bytes();
+            log_guard.append(&mut record).unwrap();
+        }
+        assert_eq!(segment_files(&test_dir), 1);
+        assert!(fs::metadata(format!("{}/0.index", test_dir)).unwrap().len() <= 112, "A sparse index should not need to grow");
+
+        // Offsets that have an index entry and ones that are scanned to
+        for i in 0..40 {
+            assert_eq!(log_guard.read(i).unwrap().value, format!("Sparse message {}", i).into_bytes());
+        }
+        let offsets: Vec<u64> = log_guard.reader(13).unwrap().map(|r| r.unwrap().offset).collect();
+        assert_eq!(offsets, (13..40).collect::<Vec<u64>>());
+
+        // Cutting between index entries
+        log_guard.truncate(30).unwrap();
+        let mut record = Record::default();
+        record.value = b"Sparse message 30 again".to_vec();
+        assert_eq!(log_guard.append(&mut record).unwrap(), 30);
+
+        log_guard.close().unwrap();
+    }
+
+    // Simulate a crash part way through writing the final frame
+    let store_path = format!("{}/0.store", test_dir);
+    let store_len = fs::metadata(&store_path).unwrap().len();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&store_path)
+        .unwrap()
+        .set_len(store_len - 3)
+        .unwrap();
+
+    // The unindexed records before the torn one are all still found
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    let mut log_guard = log.write().unwrap();
+    assert_eq!(log_guard.next_offset(), 30);
+    for i in 0..30 {
+        assert_eq!(log_guard.read(i).unwrap().value, format!("Sparse message {}", i).into_bytes());
+    }
+    assert!(log_guard.read(30).is_err(), "Torn record should have been discarded");
+
+    let mut record = Record::default();
+    record.value = b"After recovery".to_vec();
+    assert_eq!(log_guard.append(&mut record).unwrap(), 30);
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_compaction() {
+    let test_dir = setup_test_env("compaction");
+    let mut config = create_test_config(256, 1024);
+    config.compaction.tombstone_retention_ms = 60 * 60 * 1000;
+
+    let keyed = |key: &str, value: &str| {
+        let mut record = Record::default();
+        record.key = key.as_bytes().to_vec();
+        record.value = value.as_bytes().to_vec();
+        record
+    };
+
+    let (kept, active_base) = {
+        let log = Log::new(test_dir.clone(), config.clone()).unwrap();
+        let mut log_guard = log.write().unwrap();
+
+        // Four keys overwritten over and over, one record without a key and a
+        // tombstone deleting one of the keys
+        for i in 0..30 {
+            log_guard.append(&mut keyed(&format!("key-{}", i % 4), &format!("value-{}", i))).unwrap();
+        }
+        let mut unkeyed = Record::default();
+        unkeyed.value = b"no key".to_vec();
+        let unkeyed_offset = log_guard.append(&mut unkeyed).unwrap();
+        let tombstone_offset = log_guard.append(&mut keyed("key-1", "")).unwrap();
+
+        // Push the tombstone into a closed segment
+        for i in 0..10 {
+            log_guard.append(&mut keyed("key-0", &format!("later-{}", i))).unwrap();
+        }
+        let active_base = *log_guard.segments.keys().last().unwrap();
+        assert!(tombstone_offset < active_base, "The tombstone should be in a closed segment");
+
+        let before: Vec<u64> = log_guard.reader(0).unwrap().map(|r| r.unwrap().offset).collect();
+        drop(log_guard);
+        let removed = Log::compact(&log).unwrap();
+        let mut log_guard = log.write().unwrap();
+
+        // Only the latest record per key survives in the closed segments,
+        // along with the unkeyed record and the still recent tombstone. The
+        // latest key-0 is in the active segment, key-1's is the tombstone.
+        let kept: Vec<u64> = before
+            .iter()
+            .copied()
+            .filter(|&offset| offset >= active_base || [26, 27, unkeyed_offset, tombstone_offset].contains(&offset))
+            .collect();
+        assert_eq!(removed, before.len() - kept.len());
+
+        // Segments left with nothing in them are removed, moving the start
+        // of the log up to the first one left
+        let lowest = log_guard.lowest_offset().unwrap();
+        assert!(lowest > 0 && lowest <= kept[0], "Lowest offset {} should have moved up", lowest);
+        let after: Vec<u64> = log_guard.reader(lowest).unwrap().map(|r| r.unwrap().offset).collect();
+        assert_eq!(after, kept);
+
+        // Offsets are preserved and the gaps read as removed, apart from the
+        // ones before the new start of the log which are out of range
+        for offset in 0..log_guard.next_offset() {
+            match log_guard.read(offset) {
+                Ok(record) => {
+                    assert!(kept.contains(&offset), "Offset {} should have been compacted", offset);
+                    assert_eq!(record.offset, offset);
+                }
+                Err(LogError::OffsetOutOfRange { .. }) => assert!(offset < lowest),
+                Err(LogError::OffsetRemoved(removed)) => {
+                    assert_eq!(removed, offset);
+                    assert!(offset >= lowest && !kept.contains(&offset), "Offset {} should still be readable", offset);
+                }
+                Err(e) => panic!("Unexpected error reading offset {}: {}", offset, e),
+            }
+        }
+        assert!(log_guard.read(tombstone_offset).unwrap().value.is_empty());
+
+        // A reader starting inside a gap picks up at the next record
+        let from_gap: Vec<u64> = log_guard.reader(28).unwrap().map(|r| r.unwrap().offset).collect();
+        assert_eq!(from_gap, kept[2..]);
+
+        // Nothing more to remove the second time round
+        drop(log_guard);
+        assert_eq!(Log::compact(&log).unwrap(), 0);
+        let mut log_guard = log.write().unwrap();
+
+        log_guard.close().unwrap();
+        (kept, active_base)
+    };
+
+    // A compaction that never got as far as being ready is thrown away
+    fs::create_dir_all(format!("{}/compaction", test_dir)).unwrap();
+    fs::write(format!("{}/compaction/0.store", test_dir), b"half written").unwrap();
+
+    // Once the tombstone is past its retention it goes too
+    config.compaction.tombstone_retention_ms = 0;
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    let mut log_guard = log.write().unwrap();
+    assert!(!std::path::Path::new(&format!("{}/compaction", test_dir)).exists());
+
+    let lowest = log_guard.lowest_offset().unwrap();
+    let reopened: Vec<u64> = log_guard.reader(lowest).unwrap().map(|r| r.unwrap().offset).collect();
+    assert_eq!(reopened, kept);
+
+    drop(log_guard);
+    assert_eq!(Log::compact(&log).unwrap(), 1);
+    let log_guard = log.read().unwrap();
+    let tombstone_offset = 31;
+    assert!(log_guard.read(tombstone_offset).is_err(), "Expired tombstone should be removed");
+    assert_eq!(log_guard.read(27).unwrap().value, b"value-27".to_vec());
+    assert!(log_guard.next_offset() > active_base);
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_truncate_at_compacted_offset() {
+    let test_dir = setup_test_env("truncate_compacted");
+    let mut config = create_test_config(1024, 1024);
+    config.segment.max_records_per_segment = 5;
+
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    let mut log_guard = log.write().unwrap();
+    for (i, key) in ["x", "a", "a", "y", "z"].iter().enumerate() {
+        let mut record = Record::default();
+        record.key = key.as_bytes().to_vec();
+        record.value = format!("value-{}", i).into_bytes();
+        log_guard.append(&mut record).unwrap();
+    }
+    for i in 5..12 {
+        let mut record = Record::default();
+        record.value = format!("value-{}", i).into_bytes();
+        log_guard.append(&mut record).unwrap();
+    }
+
+    // Offset 1 is superseded by offset 2 and compacted away
+    drop(log_guard);
+    assert_eq!(Log::compact(&log).unwrap(), 1);
+    let mut log_guard = log.write().unwrap();
+    assert!(matches!(log_guard.read(1), Err(LogError::OffsetRemoved(1))));
+
+    // Cutting there removes everything from the next record left onwards
+    log_guard.truncate(1).unwrap();
+    assert_eq!(log_guard.next_offset(), 1);
+    assert_eq!(log_guard.read(0).unwrap().value, b"value-0".to_vec());
+    assert!(log_guard.read(2).is_err());
+
+    let mut record = Record::default();
+    record.value = b"after the cut".to_vec();
+    assert_eq!(log_guard.append(&mut record).unwrap(), 1);
+    assert_eq!(log_guard.read(1).unwrap().value, b"after the cut".to_vec());
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_truncate_in_compacted_gap() {
+    let test_dir = setup_test_env("truncate_compacted_gap");
+    let mut config = create_test_config(1024, 1024);
+    config.segment.max_records_per_segment = 5;
+
+    let append_all = |log_guard: &mut Log, keys: &[&str]| {
+        for key in keys {
+            let mut record = Record::default();
+            record.key = key.as_bytes().to_vec();
+            record.value = b"value".to_vec();
+            log_guard.append(&mut record).unwrap();
+        }
+    };
+
+    // Compaction removes offsets 3 and 4 from the end of the first segment
+    let log = Log::new(format!("{}/tail", test_dir), config.clone()).unwrap();
+    let mut log_guard = log.write().unwrap();
+    append_all(&mut log_guard, &["a", "b", "c", "d", "e", "d", "e", "", "", "", ""]);
+    drop(log_guard);
+    assert_eq!(Log::compact(&log).unwrap(), 2);
+    let mut log_guard = log.write().unwrap();
+    assert_eq!(log_guard.segments[&0].next_offset(), 3);
+
+    // Cutting in the gap leaves the next append at the cut, not after the
+    // last record left
+    log_guard.truncate(4).unwrap();
+    assert_eq!(log_guard.next_offset(), 4);
+    append_all(&mut log_guard, &["after the cut"]);
+    assert_eq!(log_guard.read(4).unwrap().key, b"after the cut".to_vec());
+    assert!(matches!(log_guard.read(3), Err(LogError::OffsetRemoved(3))));
+    drop(log_guard);
+
+    // The same with a whole segment compacted away
+    let log = Log::new(format!("{}/segment", test_dir), config).unwrap();
+    let mut log_guard = log.write().unwrap();
+    append_all(&mut log_guard, &["", "", "", "", "", "a", "b", "c", "d", "e", "a", "b", "c", "d", "e", ""]);
+    drop(log_guard);
+    assert_eq!(Log::compact(&log).unwrap(), 5);
+    let mut log_guard = log.write().unwrap();
+    assert_eq!(log_guard.segments.keys().copied().collect::<Vec<u64>>(), vec![0, 10, 15]);
+
+    log_guard.truncate(7).unwrap();
+    assert_eq!(log_guard.next_offset(), 7);
+    append_all(&mut log_guard, &["after the cut"]);
+    assert_eq!(log_guard.read(7).unwrap().key, b"after the cut".to_vec());
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_compression() {
+    let test_dir = setup_test_env("compression");
+    let mut config = create_test_config(64 * 1024, 1024);
+
+    let payload = |i: usize| {
+        format!(
+            "{{\"id\":{},\"status\":\"active\",\"tags\":[\"alpha\",\"beta\",\"gamma\"],\"description\":\"{}\"}}",
+            i,
+            "the quick brown fox jumps over the lazy dog ".repeat(8)
+        )
+        .into_bytes()
+    };
+
+    // Every codec writes into the same segment, so it ends up holding a mix
+    let codecs = [
+        config::Compression::None,
+        config::Compression::Lz4,
+        config::Compression::Zstd,
+        config::Compression::Snappy,
+    ];
+    let mut sizes = Vec::new();
+    for (round, &codec) in codecs.iter().enumerate() {
+        config.compression = codec;
+        let log = Log::new(test_dir.clone(), config.clone()).unwrap();
+        let mut log_guard = log.write().unwrap();
+
+        let before = fs::metadata(format!("{}/0.store", test_dir)).map_or(0, |m| m.len());
+        for i in round * 10..(round + 1) * 10 {
+            let mut record = Record::default();
+            record.value = payload(i);
+            log_guard.append(&mut record).unwrap();
+        }
+
+        // Too small to shrink, so it is stored as it is whatever the codec
+        let mut record = Record::default();
+        record.value = vec![round as u8];
+        log_guard.append(&mut record).unwrap();
+
+        log_guard.close().unwrap();
+        sizes.push(fs::metadata(format!("{}/0.store", test_dir)).unwrap().len() - before);
+    }
+
+    for (codec, size) in codecs.iter().zip(&sizes).skip(1) {
+        assert!(size * 2 < sizes[0], "{:?} wrote {} bytes against {} uncompressed", codec, size, sizes[0]);
+    }
+
+    // Reads give back the original records whichever codec wrote them
+    config.compression = config::Compression::None;
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    let log_guard = log.read().unwrap();
+    assert_eq!(segment_files(&test_dir), 1);
+
+    let expected: Vec<Vec<u8>> = (0..codecs.len())
+        .flat_map(|round| (round * 10..(round + 1) * 10).map(payload).chain(std::iter::once(vec![round as u8])))
+        .collect();
+    for (offset, value) in expected.iter().enumerate() {
+        assert_eq!(&log_guard.read(offset as u64).unwrap().value, value);
+    }
+
+    let values: Vec<Vec<u8>> = log_guard.reader(0).unwrap().map(|r| r.unwrap().value).collect();
+    assert_eq!(values, expected);
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_encryption() {
+    let test_dir = setup_test_env("encryption");
+    let key_file = format!("{}.keys", test_dir);
+    let first_key = "1 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f\n";
+    let second_key = "2 f0e1d2c3b4a5968778695a4b3c2d1e0f00112233445566778899aabbccddeeff\n";
+
+    let mut config = create_test_config(64 * 1024, 1024);
+    let value = |i: u64| format!("patient record {}", i).into_bytes();
+
+    fs::write(&key_file, first_key).unwrap();
+    config.encryption = Some(Arc::new(encryption::load(&key_file).unwrap()));
+    {
+        let log = Log::new(test_dir.clone(), config.clone()).unwrap();
+        let mut log_guard = log.write().unwrap();
+        for i in 0..5 {
+            let mut record = Record::default();
+            record.value = value(i);
+            log_guard.append(&mut record).unwrap();
+        }
+        log_guard.close().unwrap();
+    }
+
+    // Nothing readable ends up on disk
+    let raw = fs::read(format!("{}/0.store", test_dir)).unwrap();
+    assert!(!raw.windows(14).any(|w| w == b"patient record"), "Payloads should be encrypted");
+
+    // Rotate to a new key, compressing as well, without touching old records
+    fs::write(&key_file, format!("# rotated\n{}\n{}", first_key, second_key)).unwrap();
+    config.encryption = Some(Arc::new(encryption::load(&key_file).unwrap()));
+    config.compression = config::Compression::Zstd;
+    assert_eq!(config.encryption.as_ref().unwrap().active_key_id(), 2);
+    {
+        let log = Log::new(test_dir.clone(), config.clone()).unwrap();
+        let mut log_guard = log.write().unwrap();
+        for i in 5..10 {
+            let mut record = Record::default();
+            record.value = value(i);
+            log_guard.append(&mut record).unwrap();
+        }
+
+        for i in 0..10 {
+            assert_eq!(log_guard.read(i).unwrap().value, value(i));
+        }
+        let values: Vec<Vec<u8>> = log_guard.reader(0).unwrap().map(|r| r.unwrap().value).collect();
+        assert_eq!(values, (0..10).map(value).collect::<Vec<_>>());
+        log_guard.close().unwrap();
+    }
+
+    // Records written with a key that is no longer listed cannot be read,
+    // and are not mistaken for a torn write and cut away
+    fs::write(&key_file, second_key).unwrap();
+    config.encryption = Some(Arc::new(encryption::load(&key_file).unwrap()));
+    {
+        let log = Log::new(test_dir.clone(), config.clone()).unwrap();
+        let log_guard = log.read().unwrap();
+        assert_eq!(log_guard.next_offset(), 10);
+        for i in 0..5 {
+            let err = log_guard.read(i).unwrap_err();
+            assert!(matches!(err, LogError::Encryption(_)) && err.to_string().contains("No key with ID 1"), "{}", err);
+        }
+        assert_eq!(log_guard.read(7).unwrap().value, value(7));
+    }
+
+    config.encryption = None;
+    assert!(Log::new(test_dir.clone(), config.clone()).is_err(), "The tail cannot be checked without keys");
+
+    fs::write(&key_file, format!("{}{}", first_key, second_key)).unwrap();
+    config.encryption = Some(Arc::new(encryption::load(&key_file).unwrap()));
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    assert_eq!(log.read().unwrap().next_offset(), 10);
+    assert_eq!(log.read().unwrap().read(0).unwrap().value, value(0));
+
+    // Key files that are not usable are rejected up front
+    fs::write(&key_file, "1 abcd\n").unwrap();
+    assert!(encryption::load(&key_file).is_err());
+    fs::write(&key_file, format!("{}{}", first_key, first_key)).unwrap();
+    assert!(encryption::load(&key_file).is_err());
+    fs::write(&key_file, "# no keys\n").unwrap();
+    assert!(encryption::load(&key_file).is_err());
+
+    let _ = fs::remove_file(&key_file);
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_index_growth() {
+    let test_dir = setup_test_env("index_growth");
+    // Index files start out with room for only a few entries
+    let config = create_test_config(1024 * 1024, 64);
+
+    {
+        let log = Log::new(test_dir.clone(), config.clone()).unwrap();
+        let mut log_guard = log.write().unwrap();
+        for i in 0..500 {
+            let mut record = Record::default();
+            record.value = format!("Growing message {}", i).into_bytes();
+            record.timestamp = 1000 + i;
+            log_guard.append(&mut record).unwrap();
+        }
+
+        // Only the store decides when the segment is full
+        assert_eq!(segment_files(&test_dir), 1);
+        for i in 0..500 {
+            assert_eq!(log_guard.read(i).unwrap().value, format!("Growing message {}", i).into_bytes());
+        }
+        assert_eq!(log_guard.offset_for_timestamp(1250).unwrap(), Some(250));
+
+        log_guard.close().unwrap();
+    }
+
+    // Closing trims the files down to the entries they hold
+    let header = 16;
+    assert_eq!(fs::metadata(format!("{}/0.index", test_dir)).unwrap().len(), header + 500 * 12);
+    assert_eq!(fs::metadata(format!("{}/0.timeindex", test_dir)).unwrap().len(), header + 500 * 12);
+
+    // And they carry on growing once reopened
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    let mut log_guard = log.write().unwrap();
+    for i in 500..600 {
+        let mut record = Record::default();
+        record.value = format!("Growing message {}", i).into_bytes();
+        assert_eq!(log_guard.append(&mut record).unwrap(), i);
+    }
+    for i in (0..600).step_by(37) {
+        assert_eq!(log_guard.read(i).unwrap().value, format!("Growing message {}", i).into_bytes());
+    }
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_rolled_segment_indexes_are_trimmed() {
+    let test_dir = setup_test_env("rolled_segment_indexes_trimmed");
+    let mut config = create_test_config(1024 * 1024, 64 * 1024);
+    config.segment.max_records_per_segment = 5;
+
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    let mut log_guard = log.write().unwrap();
+    for i in 0..12 {
+        let mut record = Record::default();
+        record.value = format!("Rolled message {}", i).into_bytes();
+        record.timestamp = 1000 + i;
+        log_guard.append(&mut record).unwrap();
+    }
+
+    // The rolled segments give back their preallocated room without being
+    // closed, while the active one keeps it
+    let header = 16;
+    for base in [0, 5] {
+        assert_eq!(fs::metadata(format!("{}/{}.index", test_dir, base)).unwrap().len(), header + 5 * 12);
+        assert_eq!(fs::metadata(format!("{}/{}.timeindex", test_dir, base)).unwrap().len(), header + 5 * 12);
+    }
+    assert_eq!(fs::metadata(format!("{}/10.index", test_dir)).unwrap().len(), 64 * 1024);
+    for i in 0..12 {
+        assert_eq!(log_guard.read(i).unwrap().value, format!("Rolled message {}", i).into_bytes());
+    }
+    assert_eq!(log_guard.offset_for_timestamp(1007).unwrap(), Some(7));
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_max_records_per_segment() {
+    let test_dir = setup_test_env("max_records_per_segment");
+    let mut config = create_test_config(1024 * 1024, 1024);
+    config.segment.max_records_per_segment = 5;
+
+    {
+        let log = Log::new(test_dir.clone(), config.clone()).unwrap();
+        let mut log_guard = log.write().unwrap();
+        for i in 0..7 {
+            let mut record = Record::default();
+            record.value = format!("Counted message {}", i).into_bytes();
+            assert_eq!(log_guard.append(&mut record).unwrap(), i);
+        }
+
+        // A batch rolls part way through rather than overfilling a segment
+        let mut batch: Vec<Record> = (7..16)
+            .map(|i| {
+                let mut record = Record::default();
+                record.value = format!("Counted message {}", i).into_bytes();
+                record
+            })
+            .collect();
+        assert_eq!(log_guard.append_batch(&mut batch).unwrap(), 7..16);
+
+        assert_eq!(log_guard.segments.keys().copied().collect::<Vec<u64>>(), vec![0, 5, 10, 15]);
+        assert!(log_guard.segments.values().all(|segment| segment.records() <= 5));
+        for i in 0..16 {
+            assert_eq!(log_guard.read(i).unwrap().value, format!("Counted message {}", i).into_bytes());
+        }
+        log_guard.close().unwrap();
+    }
+
+    // The limit carries on applying to the active segment after a reopen
+    {
+        let log = Log::new(test_dir.clone(), config.clone()).unwrap();
+        let mut log_guard = log.write().unwrap();
+        for i in 16..21 {
+            let mut record = Record::default();
+            record.value = format!("Counted message {}", i).into_bytes();
+            assert_eq!(log_guard.append(&mut record).unwrap(), i);
+        }
+        assert_eq!(log_guard.segments.keys().copied().collect::<Vec<u64>>(), vec![0, 5, 10, 15, 20]);
+    }
+
+    // More records than relative offsets can address is refused outright
+    config.segment.max_records_per_segment = u32::MAX as u64 + 2;
+    assert!(Log::new(test_dir.clone(), config).is_err());
+
+    cleanup_test_env(&test_dir);
+}
+
+
+#[test]
+fn test_wal_full_segment_writes_nothing() {
+    let test_dir = setup_test_env("full_segment");
+    let mut config = create_test_config(1024 * 1024, 1024);
+    config.segment.max_records_per_segment = 3;
+
+    let store_len = {
+        let log = Log::new(test_dir.clone(), config.clone()).unwrap();
+        let mut log_guard = log.write().unwrap();
+        for i in 0..3 {
+            let mut record = Record::default();
+            record.value = format!("Counted message {}", i).into_bytes();
+            log_guard.append(&mut record).unwrap();
+        }
+
+        // Appends straight to the full segment are refused before anything
+        // reaches its store
+        let segment = log_guard.segments.get_mut(&0).unwrap();
+        let mut record = Record::default();
+        record.value = b"One too many".to_vec();
+        assert!(matches!(segment.append(&mut record), Err(LogError::SegmentFull { .. })));
+        record.offset = 5;
+        assert!(matches!(segment.append_existing(&record), Err(LogError::SegmentFull { .. })));
+        assert_eq!(segment.next_offset(), 3);
+
+        log_guard.close().unwrap();
+        fs::metadata(format!("{}/0.store", test_dir)).unwrap().len()
+    };
+
+    // No stray frame turns up past the limit on reopening either
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    let mut log_guard = log.write().unwrap();
+    assert_eq!(fs::metadata(format!("{}/0.store", test_dir)).unwrap().len(), store_len);
+    assert_eq!(log_guard.next_offset(), 3);
+
+    let mut record = Record::default();
+    record.value = b"Counted message 3".to_vec();
+    assert_eq!(log_guard.append(&mut record).unwrap(), 3);
+    assert_eq!(log_guard.segments.keys().copied().collect::<Vec<u64>>(), vec![0, 3]);
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}
+#[test]
+fn test_wal_directory_lock() {
+    let test_dir = setup_test_env("directory_lock");
+    let config = create_test_config(1024, 1024);
+
+    let log = Log::new(test_dir.clone(), config.clone()).unwrap();
+    let mut record = Record::default();
+    record.value = b"Locked in".to_vec();
+    log.write().unwrap().append(&mut record).unwrap();
+
+    // A second open of the same directory fails rather than sharing the files
+    let err = Log::new(test_dir.clone(), config.clone()).err().expect("Second open should fail");
+    assert!(matches!(err, LogError::DirectoryLocked { .. }), "{}", err);
+    assert!(err.to_string().contains("already in use"), "{}", err);
+    assert!(err.to_string().contains(&std::process::id().to_string()), "{}", err);
+
+    // The lock goes away with the log
+    log.write().unwrap().close().unwrap();
+    drop(log);
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    assert_eq!(log.read().unwrap().read(0).unwrap().value, b"Locked in".to_vec());
+
+    drop(log);
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_wal_disk_reserve() {
+    let test_dir = setup_test_env("disk_reserve");
+    let config = create_test_config(1024, 1024);
+
+    let log = Log::new(test_dir.clone(), config).unwrap();
+    let mut log_guard = log.write().unwrap();
+
+    let mut record = Record::default();
+    record.value = b"Written with room to spare".to_vec();
+    log_guard.append(&mut record).unwrap();
+    assert!(!log_guard.is_read_only());
+
+    // No volume has this much free, so the log goes read-only
+    log_guard.config.disk.reserve_bytes = u64::MAX / 2;
+    let mut record = Record::default();
+    record.value = b"Refused".to_vec();
+    let err = log_guard.append(&mut record).unwrap_err();
+    assert!(matches!(err, LogError::ReadOnly { reserve, .. } if reserve == u64::MAX / 2), "{}", err);
+    assert!(log_guard.is_read_only());
+
+    let mut batch = vec![record.clone(), record.clone()];
+    assert!(matches!(log_guard.append_batch(&mut batch), Err(LogError::ReadOnly { .. })));
+    assert_eq!(log_guard.next_offset(), 1, "Nothing should have been written");
+
+    // Reads carry on as normal
+    assert_eq!(log_guard.read(0).unwrap().value, b"Written with room to spare".to_vec());
+
+    // Retention notices once there is room again and writes resume
+    log_guard.enforce_retention().unwrap();
+    assert!(log_guard.is_read_only());
+    log_guard.config.disk.reserve_bytes = 1;
+    log_guard.enforce_retention().unwrap();
+    assert!(!log_guard.is_read_only());
+
+    assert_eq!(log_guard.append(&mut record).unwrap(), 1);
+    assert_eq!(log_guard.append_batch(&mut batch).unwrap(), 2..4);
+
+    drop(log_guard);
+    cleanup_test_env(&test_dir);
+}